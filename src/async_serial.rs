@@ -0,0 +1,160 @@
+//! Async equivalents of the serial interface, for headless tools using tokio. Enabled with the
+//! `async` feature. Framing and decoding are shared with the sync path. This doesn't include
+//! `StateCommon`'s connection state machine; on error, drop the interface and connect again.
+//! SLCAN adapters aren't supported, since `SlcanTransport` is sync-only.
+
+use std::{io, time::Duration};
+
+use anyleaf_usb::MessageType;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialStream};
+
+use crate::{
+    discover,
+    discovery::{self, MatchRule},
+    encode_frame, encode_payload,
+    frame::READ_CHUNK_SIZE,
+    ConnectionType, Error, Frame, FrameDecoder, SerialConfig,
+};
+
+pub struct AsyncSerialInterface {
+    port: SerialStream,
+    port_name: String,
+    /// Buffers received data between `receive` calls.
+    pub decoder: FrameDecoder,
+}
+
+impl AsyncSerialInterface {
+    /// Find our device using `match_rules` (see `discovery::default_rules`), and open it. If
+    /// `port_override` is set, we open that port instead of searching.
+    pub async fn connect(
+        match_rules: &[MatchRule],
+        port_override: Option<&str>,
+        config: &SerialConfig,
+    ) -> Result<Self, Error> {
+        if let Some(port_name) = port_override {
+            return Self::open(port_name, config);
+        }
+
+        // Enumeration is quick, so we don't move it off the runtime's thread.
+        match discovery::find_matching(&discover()?, match_rules) {
+            Some((port_name, ConnectionType::Usb)) => Self::open(&port_name, config),
+            Some((port_name, ConnectionType::Can)) => Err(Error::Io(io::Error::other(format!(
+                "{port_name} is an SLCAN adapter, which the async interface doesn't support"
+            )))),
+            None => Err(Error::NoDevice),
+        }
+    }
+
+    /// Open a specific port. This must be called from within a tokio runtime.
+    pub fn open(port_name: &str, config: &SerialConfig) -> Result<Self, Error> {
+        let mut port = config
+            .builder(port_name)
+            .open_native_async()
+            .map_err(|e| Error::from_serial(e, port_name))?;
+
+        if let Some(level) = config.dtr {
+            port.write_data_terminal_ready(level)
+                .map_err(|e| Error::from_serial(e, port_name))?;
+        }
+        if let Some(level) = config.rts {
+            port.write_request_to_send(level)
+                .map_err(|e| Error::from_serial(e, port_name))?;
+        }
+
+        Ok(Self {
+            port,
+            port_name: port_name.to_owned(),
+            decoder: FrameDecoder::new(),
+        })
+    }
+
+    pub fn port_name(&self) -> &str {
+        &self.port_name
+    }
+
+    /// Send a payload-less command; see `crate::send_cmd`.
+    pub async fn send_cmd<T: MessageType>(&mut self, msg_type: T) -> Result<(), Error> {
+        self.send_payload::<T, 4>(msg_type, &[]).await
+    }
+
+    /// Send a payload; see `crate::send_payload`.
+    pub async fn send_payload<T: MessageType, const N: usize>(
+        &mut self,
+        msg_type: T,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        let tx_buf = encode_payload::<T, N>(msg_type, payload)?;
+        Ok(self.port.write_all(&tx_buf).await?)
+    }
+
+    /// Send a payload, sized at runtime; see `crate::send_msg`.
+    pub async fn send_msg<T: MessageType>(
+        &mut self,
+        msg_type: T,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        Ok(self
+            .port
+            .write_all(&encode_frame(msg_type, payload)?)
+            .await?)
+    }
+
+    /// Wait for the next frame. Invalid frames are returned as errors, as with
+    /// `StateCommon::receive`. Use `tokio::time::timeout` to bound the wait.
+    pub async fn receive<T: MessageType + TryFrom<u8>>(&mut self) -> Result<Frame<T>, Error> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+
+        loop {
+            if let Some(result) = self.decoder.next_frame() {
+                return Ok(result?);
+            }
+
+            let n = self.port.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.decoder.push(&chunk[..n]);
+        }
+    }
+
+    /// Send a message, and wait for a reply of type `expected_reply_type`; see
+    /// `StateCommon::request`.
+    pub async fn request<T: MessageType + TryFrom<u8>>(
+        &mut self,
+        msg_type: T,
+        payload: &[u8],
+        expected_reply_type: T,
+        timeout: Duration,
+        retries: u8,
+    ) -> Result<Vec<u8>, Error> {
+        let tx_buf = encode_frame(msg_type, payload)?;
+
+        for _ in 0..=retries {
+            self.port.write_all(&tx_buf).await?;
+
+            let reply = tokio::time::timeout(timeout, async {
+                loop {
+                    match self.receive::<T>().await {
+                        Ok(frame) => {
+                            if frame.msg_type.val() == expected_reply_type.val() {
+                                return Ok(frame.payload);
+                            }
+                        }
+                        // A corrupted frame may have been our reply; keep waiting, and let the
+                        // retry handle it.
+                        Err(Error::Crc(_) | Error::ProtocolMismatch(_)) => (),
+                        Err(e) => return Err(e),
+                    }
+                }
+            })
+            .await;
+
+            if let Ok(result) = reply {
+                return result;
+            }
+        }
+
+        Err(Error::Timeout)
+    }
+}
@@ -0,0 +1,417 @@
+//! Records traffic to a file, and replays it as a fake transport. This lets us reproduce field
+//! bugs using the exact byte stream an app saw.
+//!
+//! The format is plain text, one chunk per line: microseconds since the capture started, the
+//! direction (`TX` or `RX`, from the app's perspective), then the bytes in hex. Lines starting
+//! with `#` are comments.
+
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{pcapng::SharedPcapng, Error, Transport};
+
+const HEADER: &str = "# pc_interface_shared capture v1";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Direction {
+    /// Sent by the app.
+    Tx,
+    /// Received by the app.
+    Rx,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Tx => "TX",
+            Self::Rx => "RX",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CaptureEntry {
+    /// Time since the capture started.
+    pub time: Duration,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+/// Writes captured traffic. Shared between the transports being captured; see `SharedCapture`.
+/// If a write fails, capturing stops, instead of the error reaching the transport; a full disk
+/// shouldn't take down the connection.
+pub struct Capture {
+    writer: Option<Box<dyn Write + Send>>,
+    start: Instant,
+}
+
+/// A capture shared between transports, eg across reconnects, or with the I/O worker.
+pub type SharedCapture = Arc<Mutex<Capture>>;
+
+impl Capture {
+    /// Start a capture to any writer.
+    pub fn new(mut writer: Box<dyn Write + Send>) -> io::Result<Self> {
+        let unix_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        writeln!(writer, "{HEADER}")?;
+        writeln!(writer, "# Started at unix time {unix_time}")?;
+        writer.flush()?;
+
+        Ok(Self {
+            writer: Some(writer),
+            start: Instant::now(),
+        })
+    }
+
+    /// Start a capture to a file, overwriting it if it exists.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Self::new(Box::new(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn record(&mut self, direction: Direction, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+
+        let mut line = format!(
+            "{} {} ",
+            self.start.elapsed().as_micros(),
+            direction.as_str()
+        );
+        for byte in bytes {
+            write!(line, "{byte:02x}").unwrap();
+        }
+
+        // Flush each line, so the capture survives the app being killed or aborting; that's
+        // often how the bugs we're capturing end.
+        if writeln!(writer, "{line}")
+            .and_then(|()| writer.flush())
+            .is_err()
+        {
+            self.writer = None;
+        }
+    }
+
+    /// Flush and close the output. Further records are ignored.
+    pub fn stop(&mut self) -> io::Result<()> {
+        match self.writer.take() {
+            Some(mut writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.writer.is_some()
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
+
+/// Wraps a transport, recording everything read and written.
+pub struct CaptureTransport {
+    pub inner: Box<dyn Transport>,
+    pub capture: SharedCapture,
+}
+
+impl CaptureTransport {
+    pub fn new(inner: Box<dyn Transport>, capture: SharedCapture) -> Self {
+        Self { inner, capture }
+    }
+}
+
+impl Transport for CaptureTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.capture
+            .lock()
+            .unwrap()
+            .record(Direction::Rx, &buf[..n]);
+        Ok(n)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write(buf)?;
+        self.capture.lock().unwrap().record(Direction::Tx, buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn describe(&self) -> String {
+        format!("{} (capturing)", self.inner.describe())
+    }
+
+    fn set_pcap(&mut self, pcap: Option<SharedPcapng>) {
+        self.inner.set_pcap(pcap)
+    }
+}
+
+/// Read a capture file.
+pub fn load_capture(path: &Path) -> Result<Vec<CaptureEntry>, Error> {
+    read_capture(BufReader::new(File::open(path)?))
+}
+
+fn read_capture(reader: impl BufRead) -> Result<Vec<CaptureEntry>, Error> {
+    let mut result = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        result.push(parse_line(line).ok_or_else(|| {
+            Error::ProtocolMismatch(format!("Invalid capture line {}: {line}", i + 1))
+        })?);
+    }
+
+    Ok(result)
+}
+
+/// The wall-clock time a capture started, from its header.
+pub fn capture_start(path: &Path) -> Option<SystemTime> {
+    let reader = BufReader::new(File::open(path).ok()?);
+
+    for line in reader.lines() {
+        let line = line.ok()?;
+        if !line.starts_with('#') {
+            break;
+        }
+
+        if let Some(secs) = line.strip_prefix("# Started at unix time ") {
+            return Some(UNIX_EPOCH + Duration::from_secs(secs.trim().parse().ok()?));
+        }
+    }
+
+    None
+}
+
+fn parse_line(line: &str) -> Option<CaptureEntry> {
+    let mut parts = line.split_whitespace();
+
+    let time = Duration::from_micros(parts.next()?.parse().ok()?);
+
+    let direction = match parts.next()? {
+        "TX" => Direction::Tx,
+        "RX" => Direction::Rx,
+        _ => return None,
+    };
+
+    let hex = parts.next()?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    let mut bytes = Vec::with_capacity(hex.len() / 2);
+    for i in (0..hex.len()).step_by(2) {
+        bytes.push(u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()?);
+    }
+
+    Some(CaptureEntry {
+        time,
+        direction,
+        bytes,
+    })
+}
+
+/// A fake transport that plays back the received side of a capture. What the app writes is
+/// compared against the capture's sent side, to help spot where behavior diverges.
+pub struct ReplayTransport {
+    rx: VecDeque<CaptureEntry>,
+    tx: VecDeque<u8>,
+    /// If true, received data is delivered with the capture's timing. Otherwise, as fast as
+    /// it's read.
+    pub realtime: bool,
+    /// Writes that didn't match what the app sent during the capture.
+    pub mismatched_writes: u32,
+    start: Option<Instant>,
+    name: String,
+}
+
+impl ReplayTransport {
+    pub fn new(entries: Vec<CaptureEntry>, realtime: bool) -> Self {
+        let mut rx = VecDeque::new();
+        let mut tx = VecDeque::new();
+
+        for entry in entries {
+            match entry.direction {
+                Direction::Rx => rx.push_back(entry),
+                Direction::Tx => tx.extend(entry.bytes),
+            }
+        }
+
+        Self {
+            rx,
+            tx,
+            realtime,
+            mismatched_writes: 0,
+            start: None,
+            name: "Replay".to_owned(),
+        }
+    }
+
+    pub fn open(path: &Path, realtime: bool) -> Result<Self, Error> {
+        let mut result = Self::new(load_capture(path)?, realtime);
+        result.name = format!("Replay: {}", path.display());
+        Ok(result)
+    }
+
+    /// True once all received data has been played back.
+    pub fn is_finished(&self) -> bool {
+        self.rx.is_empty()
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(entry) = self.rx.front_mut() else {
+            // Behave like an idle port.
+            thread::sleep(Duration::from_millis(10));
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "End of capture"));
+        };
+
+        if self.realtime {
+            // Timing starts with the first read, since that's when the app is ready for data.
+            let start = *self.start.get_or_insert_with(Instant::now);
+            let elapsed = start.elapsed();
+
+            if entry.time > elapsed {
+                // Wait at most a port timeout's worth, like a real port.
+                let wait = entry.time - elapsed;
+                if wait > Duration::from_millis(10) {
+                    thread::sleep(Duration::from_millis(10));
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "No data yet"));
+                }
+                thread::sleep(wait);
+            }
+        }
+
+        let n = buf.len().min(entry.bytes.len());
+        buf[..n].copy_from_slice(&entry.bytes[..n]);
+        entry.bytes.drain(..n);
+
+        if entry.bytes.is_empty() {
+            self.rx.pop_front();
+        }
+
+        Ok(n)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        let n = buf.len().min(self.tx.len());
+        let expected: Vec<u8> = self.tx.drain(..n).collect();
+
+        if expected != buf {
+            self.mismatched_writes += 1;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        self.name.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A writer whose output can be read back after it's moved into a `Capture`.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let buf = SharedBuf::default();
+        let mut capture = Capture::new(Box::new(buf.clone())).unwrap();
+        capture.record(Direction::Tx, &[1, 2]);
+        capture.record(Direction::Rx, &[3, 4, 5]);
+        capture.record(Direction::Rx, &[]);
+        capture.record(Direction::Tx, &[0xff]);
+        capture.stop().unwrap();
+
+        let written = buf.0.lock().unwrap().clone();
+        let entries = read_capture(&written[..]).unwrap();
+
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|e| (e.direction, e.bytes.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (Direction::Tx, vec![1, 2]),
+                (Direction::Rx, vec![3, 4, 5]),
+                (Direction::Tx, vec![0xff]),
+            ]
+        );
+        assert!(entries.windows(2).all(|w| w[0].time <= w[1].time));
+
+        let mut replay = ReplayTransport::new(entries, false);
+        replay.write(&[1, 2]).unwrap();
+
+        let mut rx = [0; 2];
+        assert_eq!(replay.read(&mut rx).unwrap(), 2);
+        assert_eq!(rx, [3, 4]);
+        assert_eq!(replay.read(&mut rx).unwrap(), 1);
+        assert_eq!(rx[0], 5);
+        assert!(replay.is_finished());
+        assert_eq!(
+            replay.read(&mut rx).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+
+        replay.write(&[0xfe]).unwrap();
+        assert_eq!(replay.mismatched_writes, 1);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for line in ["10 TX 0a0", "10 XX 0a", "10 RX 0g", "x TX 0a", "10 TX"] {
+            let capture = format!("{HEADER}\n\n{line}\n");
+
+            match read_capture(capture.as_bytes()) {
+                Err(Error::ProtocolMismatch(msg)) => assert!(msg.contains("line 3"), "{msg}"),
+                r => panic!("{line:?} was accepted: {:?}", r.map(|e| e.len())),
+            }
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! Serial port settings. The defaults suit our USB devices; SLCAN adapters, and some boards,
+//! need others.
+
+use std::time::Duration;
+
+use serialport::{DataBits, FlowControl, Parity, SerialPortBuilder, StopBits};
+
+use crate::{
+    slcan::{Bitrate, DEFAULT_TX_ID},
+    BAUD, DISCONNECTED_TIMEOUT_MS, TIMEOUT_MILIS,
+};
+
+#[derive(Clone, Debug)]
+pub struct SerialConfig {
+    /// Ignored by USB CDC-ACM devices, but used by USB-UART bridges, and SLCAN adapters.
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    /// How long a read waits for data. This also paces the I/O worker's loop.
+    pub read_timeout: Duration,
+    /// If the device doesn't respond to a query within this, the connection is marked stale.
+    pub disconnect_timeout: Duration,
+    /// Drive DTR high (`true`) or low (`false`) after opening. `None` leaves it as the OS sets
+    /// it. Some boards reset when DTR toggles.
+    pub dtr: Option<bool>,
+    /// As with `dtr`, for RTS.
+    pub rts: Option<bool>,
+    /// The CAN bus bitrate, when connecting through an SLCAN adapter.
+    pub can_bitrate: Bitrate,
+    /// The CAN ID we send on, through an SLCAN adapter.
+    pub can_tx_id: u32,
+    /// If set, only frames with this CAN ID are passed up; otherwise, data from every node on
+    /// the bus is merged into what we receive.
+    pub can_rx_id: Option<u32>,
+    /// Send on 29-bit CAN IDs instead of 11-bit ones.
+    pub can_extended: bool,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            baud: BAUD,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            read_timeout: Duration::from_millis(TIMEOUT_MILIS),
+            disconnect_timeout: Duration::from_millis(DISCONNECTED_TIMEOUT_MS),
+            dtr: None,
+            rts: None,
+            can_bitrate: Bitrate::default(),
+            can_tx_id: DEFAULT_TX_ID,
+            can_rx_id: None,
+            can_extended: false,
+        }
+    }
+}
+
+impl SerialConfig {
+    /// Apply settings that are set before opening.
+    pub(crate) fn builder(&self, port_name: &str) -> SerialPortBuilder {
+        serialport::new(port_name, self.baud)
+            .data_bits(self.data_bits)
+            .parity(self.parity)
+            .stop_bits(self.stop_bits)
+            .flow_control(self.flow_control)
+            .timeout(self.read_timeout)
+    }
+}
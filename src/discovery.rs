@@ -0,0 +1,277 @@
+//! Finds serial ports that may be our device. `MatchRule`s pick one automatically; by serial
+//! number, VID/PID, or name. When that isn't enough, eg several devices share a serial number,
+//! `discover` lists the candidates; pass the chosen port's name to
+//! `StateCommon::connect_to_port`.
+
+use serialport::SerialPortType;
+
+use crate::{ConnectionType, Error, SLCAN_PRODUCT_KEYWORD};
+
+/// A serial port, with the USB details we use to identify devices. Non-USB ports (eg PTYs, or
+/// built-in UARTs) are included, without these details.
+#[derive(Clone, PartialEq, Debug)]
+pub struct PortCandidate {
+    /// eg `/dev/ttyACM0`, or `COM3`.
+    pub port_name: String,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    /// SLCAN adapters are detected by product name; everything else is assumed to be USB.
+    pub connection_type: ConnectionType,
+}
+
+impl PortCandidate {
+    pub fn is_usb(&self) -> bool {
+        self.vid.is_some()
+    }
+}
+
+/// List all serial ports, in the order the OS reports them.
+pub fn discover() -> Result<Vec<PortCandidate>, Error> {
+    let ports = serialport::available_ports().map_err(|e| Error::Io(e.into()))?;
+
+    Ok(ports
+        .into_iter()
+        .map(|port_info| match port_info.port_type {
+            SerialPortType::UsbPort(info) => {
+                let is_slcan = info
+                    .product
+                    .as_ref()
+                    .is_some_and(|p| p.to_lowercase().contains(SLCAN_PRODUCT_KEYWORD));
+
+                PortCandidate {
+                    port_name: port_info.port_name,
+                    vid: Some(info.vid),
+                    pid: Some(info.pid),
+                    manufacturer: info.manufacturer,
+                    product: info.product,
+                    serial_number: info.serial_number,
+                    connection_type: if is_slcan {
+                        ConnectionType::Can
+                    } else {
+                        ConnectionType::Usb
+                    },
+                }
+            }
+            _ => PortCandidate {
+                port_name: port_info.port_name,
+                vid: None,
+                pid: None,
+                manufacturer: None,
+                product: None,
+                serial_number: None,
+                connection_type: ConnectionType::Usb,
+            },
+        })
+        .collect())
+}
+
+/// The connection type of a port, by name. Ports that aren't listed (eg PTYs on some platforms)
+/// are assumed to be USB.
+pub fn connection_type_of(port_name: &str) -> ConnectionType {
+    discover()
+        .ok()
+        .and_then(|ports| ports.into_iter().find(|p| p.port_name == port_name))
+        .map(|p| p.connection_type)
+        .unwrap_or_default()
+}
+
+/// Identifies our device among discovered ports. Each field that's set must match; unset fields
+/// match anything, and empty strings match nothing. String comparisons are case-insensitive,
+/// except for serial numbers.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct MatchRule {
+    /// The exact port name; eg for a device without a serial number.
+    pub port_name: Option<String>,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    /// A substring of the manufacturer string.
+    pub manufacturer: Option<String>,
+    /// A substring of the product string.
+    pub product: Option<String>,
+    /// The full serial number.
+    pub serial_number: Option<String>,
+    /// A prefix of the serial number; eg to match any board from a batch. Prefer `serial_number`
+    /// where possible, since this can match sibling boards.
+    pub serial_prefix: Option<String>,
+    /// How to open a matching port. If `None`, the detected type is used.
+    pub connection_type: Option<ConnectionType>,
+}
+
+impl MatchRule {
+    /// Match a USB device by serial number.
+    pub fn serial_number(serial_number: &str) -> Self {
+        Self {
+            serial_number: Some(serial_number.to_owned()),
+            connection_type: Some(ConnectionType::Usb),
+            ..Default::default()
+        }
+    }
+
+    /// Match a specific port, by name.
+    pub fn port_name(port_name: &str) -> Self {
+        Self {
+            port_name: Some(port_name.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    pub fn vid_pid(vid: u16, pid: u16) -> Self {
+        Self {
+            vid: Some(vid),
+            pid: Some(pid),
+            ..Default::default()
+        }
+    }
+
+    /// Match SLCAN adapters by product name.
+    pub fn slcan() -> Self {
+        Self {
+            product: Some(SLCAN_PRODUCT_KEYWORD.to_owned()),
+            connection_type: Some(ConnectionType::Can),
+            ..Default::default()
+        }
+    }
+
+    pub fn matches(&self, port: &PortCandidate) -> bool {
+        fn check(
+            field: &Option<String>,
+            pattern: &Option<String>,
+            f: impl Fn(&str, &str) -> bool,
+        ) -> bool {
+            match pattern {
+                Some(pattern) => {
+                    !pattern.is_empty() && field.as_ref().is_some_and(|field| f(field, pattern))
+                }
+                None => true,
+            }
+        }
+
+        fn contains(field: &str, pattern: &str) -> bool {
+            field.to_lowercase().contains(&pattern.to_lowercase())
+        }
+
+        self.port_name
+            .as_ref()
+            .is_none_or(|name| !name.is_empty() && *name == port.port_name)
+            && self.vid.is_none_or(|vid| port.vid == Some(vid))
+            && self.pid.is_none_or(|pid| port.pid == Some(pid))
+            && check(&port.manufacturer, &self.manufacturer, contains)
+            && check(&port.product, &self.product, contains)
+            && check(&port.serial_number, &self.serial_number, |sn, p| sn == p)
+            && check(&port.serial_number, &self.serial_prefix, |sn, p| {
+                sn.starts_with(p)
+            })
+    }
+}
+
+/// The rules used when none are configured: our USB device by serial number, then any SLCAN
+/// adapter.
+pub fn default_rules(usb_serial_number: &str) -> Vec<MatchRule> {
+    vec![
+        MatchRule::serial_number(usb_serial_number),
+        MatchRule::slcan(),
+    ]
+}
+
+/// Find the first port matching `rules`. Rules are in priority order: a port matching an
+/// earlier rule is chosen over one matching a later rule, regardless of the order the OS lists
+/// them in. Put CAN rules first to prefer CAN adapters over USB.
+pub fn find_matching(
+    ports: &[PortCandidate],
+    rules: &[MatchRule],
+) -> Option<(String, ConnectionType)> {
+    for rule in rules {
+        if let Some(port) = ports.iter().find(|port| rule.matches(port)) {
+            let connection_type = rule.connection_type.unwrap_or(port.connection_type);
+            return Some((port.port_name.clone(), connection_type));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(port_name: &str, serial_number: Option<&str>) -> PortCandidate {
+        PortCandidate {
+            port_name: port_name.to_owned(),
+            vid: Some(0x1209),
+            pid: Some(0x0001),
+            manufacturer: Some("AnyLeaf".to_owned()),
+            product: Some("Flight controller".to_owned()),
+            serial_number: serial_number.map(str::to_owned),
+            connection_type: ConnectionType::Usb,
+        }
+    }
+
+    #[test]
+    fn serial_number_is_exact() {
+        let rule = MatchRule::serial_number("ABC");
+
+        assert!(rule.matches(&port("a", Some("ABC"))));
+        assert!(!rule.matches(&port("b", Some("ABC123"))));
+        assert!(!rule.matches(&port("c", Some("abc"))));
+        assert!(!rule.matches(&port("d", None)));
+    }
+
+    #[test]
+    fn serial_prefix() {
+        let rule = MatchRule {
+            serial_prefix: Some("ABC".to_owned()),
+            ..Default::default()
+        };
+
+        assert!(rule.matches(&port("a", Some("ABC"))));
+        assert!(rule.matches(&port("b", Some("ABC123"))));
+        assert!(!rule.matches(&port("c", Some("XABC"))));
+    }
+
+    #[test]
+    fn empty_patterns_match_nothing() {
+        let rules = [
+            MatchRule::serial_number(""),
+            MatchRule {
+                serial_prefix: Some(String::new()),
+                ..Default::default()
+            },
+            MatchRule {
+                product: Some(String::new()),
+                ..Default::default()
+            },
+        ];
+
+        for rule in rules {
+            assert!(!rule.matches(&port("a", Some("ABC"))));
+            assert!(!rule.matches(&port("b", Some(""))));
+        }
+    }
+
+    #[test]
+    fn port_name() {
+        let rule = MatchRule::port_name("b");
+
+        assert!(!rule.matches(&port("a", Some("ABC"))));
+        assert!(rule.matches(&port("b", None)));
+        assert!(!MatchRule::port_name("").matches(&port("", None)));
+    }
+
+    #[test]
+    fn rule_order_is_priority() {
+        let ports = [port("a", Some("ABC")), port("b", Some("DEF"))];
+        let rules = [
+            MatchRule::serial_number("DEF"),
+            MatchRule::serial_number("ABC"),
+        ];
+
+        assert_eq!(
+            find_matching(&ports, &rules),
+            Some(("b".to_owned(), ConnectionType::Usb))
+        );
+        assert_eq!(find_matching(&ports, &default_rules("")), None);
+    }
+}
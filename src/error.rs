@@ -0,0 +1,124 @@
+//! The crate-level error type. Variants are chosen so the GUI can show an actionable message.
+
+use std::{fmt, io};
+
+use crate::{ConnectionStatus, DecodeError};
+
+#[derive(Debug)]
+pub enum Error {
+    /// No port matching our device was found.
+    NoDevice,
+    /// No device with this key has been added to the `DeviceManager`.
+    UnknownDevice(String),
+    /// We found the device, but don't have permission to open its port. Contains the port name.
+    PermissionDenied(String),
+    /// We found the device, but another program has its port open. Contains the port name.
+    PortBusy(String),
+    /// The device didn't respond in time.
+    Timeout,
+    /// A received frame failed its CRC check. Contains the message type byte.
+    Crc(u8),
+    /// The payload doesn't fit in the message. Sizes are in bytes.
+    PayloadTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// The device sent something we don't understand, or a message doesn't match the size its
+    /// type specifies. This usually means the firmware and app are out of sync.
+    ProtocolMismatch(String),
+    Io(io::Error),
+    /// The window icon couldn't be loaded or decoded.
+    Icon(String),
+    /// The GUI failed to start, or exited with an error.
+    Gui(String),
+}
+
+impl Error {
+    /// Categorize a failure to open a port.
+    pub fn from_serial(e: serialport::Error, port_name: &str) -> Self {
+        match e.kind {
+            serialport::ErrorKind::NoDevice
+            | serialport::ErrorKind::Io(io::ErrorKind::NotFound) => Self::NoDevice,
+            serialport::ErrorKind::Io(io::ErrorKind::PermissionDenied) => {
+                Self::PermissionDenied(port_name.to_owned())
+            }
+            serialport::ErrorKind::Io(io::ErrorKind::ResourceBusy) => {
+                Self::PortBusy(port_name.to_owned())
+            }
+            // Serialport reports `EBUSY` as an unknown error, so check the description too.
+            _ if e.description.to_lowercase().contains("busy") => {
+                Self::PortBusy(port_name.to_owned())
+            }
+            _ => Self::Io(e.into()),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoDevice => write!(f, "No device found. Is it plugged in?"),
+            Self::UnknownDevice(key) => write!(f, "Unknown device: {key}"),
+            Self::PermissionDenied(port) => write!(
+                f,
+                "Permission denied opening {port}. On Linux, add your user to the `dialout` group."
+            ),
+            Self::PortBusy(port) => {
+                write!(
+                    f,
+                    "{port} is in use by another program. Close it, and try again."
+                )
+            }
+            Self::Timeout => write!(f, "The device didn't respond in time"),
+            Self::Crc(msg_type) => write!(f, "CRC mismatch on message type {msg_type}"),
+            Self::PayloadTooLarge { size, max } => {
+                write!(f, "Payload too large: {size} bytes; the maximum is {max}")
+            }
+            Self::ProtocolMismatch(details) => write!(
+                f,
+                "Protocol mismatch: {details}. Are the firmware and app versions compatible?"
+            ),
+            Self::Io(e) => write!(f, "I/O error: {e}"),
+            Self::Icon(details) => write!(f, "Unable to load the window icon: {details}"),
+            Self::Gui(details) => write!(f, "GUI error: {details}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut => Self::Timeout,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::BadCrc(msg_type) => Self::Crc(msg_type),
+            _ => Self::ProtocolMismatch(e.to_string()),
+        }
+    }
+}
+
+impl From<&Error> for ConnectionStatus {
+    fn from(e: &Error) -> Self {
+        match e {
+            Error::NoDevice => Self::NotConnected,
+            Error::PermissionDenied(_) => Self::PermissionDenied,
+            Error::PortBusy(_) => Self::PortBusy,
+            _ => Self::Error(e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! Receive-side counterpart to `send_payload`: A streaming decoder that accepts arbitrary byte
+//! chunks (eg from `Transport::read`), resynchronises on `MSG_START`, and yields validated frames.
+
+use std::{fmt, io, marker::PhantomData};
+
+use anyleaf_usb::{self, MessageType, MsgType, MAVLINK_SIZE, MSG_START, PAYLOAD_START_I};
+
+use crate::Transport;
+
+/// Size of the read buffer used by `FrameDecoder::read_from`.
+pub(crate) const READ_CHUNK_SIZE: usize = 256;
+
+/// If we've buffered this many bytes without finding a frame, something's wrong; drop them.
+const MAX_BUF_SIZE: usize = 4_096;
+
+/// A validated frame, as received from a device.
+#[derive(Clone, Debug)]
+pub struct Frame<T> {
+    /// The device code byte; identifies the sender.
+    pub device_code: u8,
+    pub msg_type: T,
+    /// The payload only; excludes the header and CRC.
+    pub payload: Vec<u8>,
+}
+
+impl<T: MessageType> Frame<T> {
+    /// Erase the message type; eg to pass frames between threads without making everything
+    /// generic.
+    pub fn into_raw(self) -> Frame<u8> {
+        Frame {
+            device_code: self.device_code,
+            msg_type: self.msg_type.val(),
+            payload: self.payload,
+        }
+    }
+}
+
+impl Frame<u8> {
+    /// Encode the frame as sent on the wire, including header and CRC.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let crc_i = PAYLOAD_START_I + self.payload.len();
+
+        let mut result = Vec::with_capacity(crc_i + 1);
+        result.extend_from_slice(&[MSG_START, self.device_code, self.msg_type]);
+        result.extend_from_slice(&self.payload);
+        result.push(anyleaf_usb::calc_crc(
+            &anyleaf_usb::CRC_LUT,
+            &result,
+            crc_i as u8,
+        ));
+
+        result
+    }
+
+    /// Convert a raw frame back to a typed one.
+    pub fn typed<T: TryFrom<u8>>(self) -> Result<Frame<T>, DecodeError> {
+        let msg_type =
+            T::try_from(self.msg_type).map_err(|_| DecodeError::UnknownType(self.msg_type))?;
+
+        Ok(Frame {
+            device_code: self.device_code,
+            msg_type,
+            payload: self.payload,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DecodeError {
+    /// The CRC byte didn't match the one we computed. Contains the message type byte.
+    BadCrc(u8),
+    /// The message type byte didn't map to a known message type.
+    UnknownType(u8),
+    /// A partial frame was discarded. Contains the number of bytes dropped.
+    Truncated(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadCrc(t) => write!(f, "CRC mismatch on message type {t}"),
+            Self::UnknownType(t) => write!(f, "Unknown message type {t}"),
+            Self::Truncated(n) => write!(f, "Truncated frame; dropped {n} bytes"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Running counts of what the decoder has seen. Useful for diagnosing flaky links.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct DecodeStats {
+    pub frames: u64,
+    pub bad_crc: u64,
+    pub unknown_type: u64,
+    pub truncated: u64,
+    /// Bytes skipped while searching for `MSG_START`.
+    pub discarded_bytes: u64,
+}
+
+/// Buffers incoming bytes, and splits them into frames. It's not tied to a specific message
+/// type enum, so it can be stored in non-generic state; the type is chosen when pulling frames.
+#[derive(Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    pub stats: DecodeStats,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add received bytes to the buffer. They can be split at any point.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Read whatever is available from the port into the buffer. A read timeout is treated as
+    /// no data, since that's the normal case with our short port timeout.
+    pub fn read_from(&mut self, port: &mut (impl Transport + ?Sized)) -> io::Result<usize> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+
+        match port.read(&mut chunk) {
+            Ok(n) => {
+                self.push(&chunk[..n]);
+                Ok(n)
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The last `n` bytes pushed, if they're still buffered; eg to show what `read_from` read.
+    pub fn tail(&self, n: usize) -> &[u8] {
+        &self.buf[self.buf.len().saturating_sub(n)..]
+    }
+
+    /// The number of bytes currently buffered, but not yet part of a decoded frame.
+    pub fn pending(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Discard any buffered partial frame, eg after a timeout or reconnect. Counted as truncated
+    /// if there was anything to discard.
+    pub fn reset(&mut self) -> Option<DecodeError> {
+        let n = self.buf.len();
+        self.buf.clear();
+
+        if n == 0 {
+            return None;
+        }
+        self.stats.truncated += 1;
+        Some(DecodeError::Truncated(n))
+    }
+
+    /// Pull the next frame from the buffer. Returns `None` once there isn't a complete frame
+    /// buffered. Errors are returned (and counted) individually, so call this in a loop until
+    /// it returns `None`.
+    pub fn next_frame<T: MessageType + TryFrom<u8>>(
+        &mut self,
+    ) -> Option<Result<Frame<T>, DecodeError>> {
+        // Resync: Drop everything before the start byte.
+        match self.buf.iter().position(|b| *b == MSG_START) {
+            Some(0) => (),
+            Some(i) => self.discard(i),
+            None => {
+                let n = self.buf.len();
+                self.discard(n);
+                return None;
+            }
+        }
+
+        if self.buf.len() < PAYLOAD_START_I {
+            return None;
+        }
+
+        let type_byte = self.buf[2];
+        let msg_type = match T::try_from(type_byte) {
+            Ok(t) => t,
+            Err(_) => {
+                // Likely a start byte that's actually part of a payload, or line noise.
+                self.discard(1);
+                self.stats.unknown_type += 1;
+                return Some(Err(DecodeError::UnknownType(type_byte)));
+            }
+        };
+
+        let payload_size = if type_byte == MsgType::Telemetry.val() {
+            // The MAVLink length byte is the second payload byte.
+            match self.buf.get(PAYLOAD_START_I + 1) {
+                Some(len) => *len as usize + MAVLINK_SIZE,
+                None => return None,
+            }
+        } else {
+            msg_type.payload_size()
+        };
+
+        let crc_i = PAYLOAD_START_I + payload_size;
+
+        if self.buf.len() <= crc_i {
+            if self.buf.len() > MAX_BUF_SIZE {
+                // Shouldn't happen with our sizes; guards against a runaway buffer.
+                let n = self.buf.len();
+                self.buf.clear();
+                self.stats.truncated += 1;
+                return Some(Err(DecodeError::Truncated(n)));
+            }
+            return None;
+        }
+
+        let crc = anyleaf_usb::calc_crc(&anyleaf_usb::CRC_LUT, &self.buf[..crc_i], crc_i as u8);
+
+        if crc != self.buf[crc_i] {
+            // Only drop the start byte; a real frame may begin inside this one.
+            self.discard(1);
+            self.stats.bad_crc += 1;
+            return Some(Err(DecodeError::BadCrc(type_byte)));
+        }
+
+        let frame = Frame {
+            device_code: self.buf[1],
+            msg_type,
+            payload: self.buf[PAYLOAD_START_I..crc_i].to_vec(),
+        };
+
+        self.buf.drain(..crc_i + 1);
+        self.stats.frames += 1;
+
+        Some(Ok(frame))
+    }
+
+    /// Iterate over all complete frames currently buffered.
+    pub fn frames<T: MessageType + TryFrom<u8>>(&mut self) -> Frames<'_, T> {
+        Frames {
+            decoder: self,
+            _msg_type: PhantomData,
+        }
+    }
+
+    fn discard(&mut self, n: usize) {
+        self.buf.drain(..n);
+        self.stats.discarded_bytes += n as u64;
+    }
+}
+
+/// Iterator over buffered frames; see `FrameDecoder::frames`.
+pub struct Frames<'a, T> {
+    decoder: &'a mut FrameDecoder,
+    _msg_type: PhantomData<T>,
+}
+
+impl<T: MessageType + TryFrom<u8>> Iterator for Frames<'_, T> {
+    type Item = Result<Frame<T>, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.decoder.next_frame()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use anyleaf_usb::DEVICE_CODE_PC;
+
+    use super::*;
+    use crate::encode_frame_from;
+
+    /// A message type for tests, independent of the real device's set. Telemetry uses the real
+    /// type byte, since the decoder sizes it from the MAVLink length byte.
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub(crate) enum TestMsg {
+        Ping,
+        Params,
+        Telemetry,
+    }
+
+    impl MessageType for TestMsg {
+        fn val(&self) -> u8 {
+            match self {
+                Self::Ping => 1,
+                Self::Params => 2,
+                Self::Telemetry => MsgType::Telemetry.val(),
+            }
+        }
+
+        fn payload_size(&self) -> usize {
+            match self {
+                Self::Ping | Self::Telemetry => 0,
+                Self::Params => 4,
+            }
+        }
+    }
+
+    impl TryFrom<u8> for TestMsg {
+        type Error = ();
+
+        fn try_from(val: u8) -> Result<Self, ()> {
+            [Self::Ping, Self::Params, Self::Telemetry]
+                .into_iter()
+                .find(|t| t.val() == val)
+                .ok_or(())
+        }
+    }
+
+    /// Not a `TestMsg`, nor the start byte.
+    const UNKNOWN_TYPE: u8 = 0xee;
+
+    fn params() -> Vec<u8> {
+        encode_frame_from(DEVICE_CODE_PC, TestMsg::Params, &[1, 2, 3, 4]).unwrap()
+    }
+
+    #[test]
+    fn decodes_frame() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&params());
+
+        let frame = decoder.next_frame::<TestMsg>().unwrap().unwrap();
+        assert_eq!(frame.device_code, DEVICE_CODE_PC);
+        assert_eq!(frame.msg_type, TestMsg::Params);
+        assert_eq!(frame.payload, [1, 2, 3, 4]);
+
+        assert!(decoder.next_frame::<TestMsg>().is_none());
+        assert_eq!(decoder.pending(), 0);
+        assert_eq!(decoder.stats.frames, 1);
+    }
+
+    #[test]
+    fn resyncs_after_garbage() {
+        let garbage = [0x11, 0x22, 0x33];
+        assert!(!garbage.contains(&MSG_START));
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&garbage);
+        decoder.push(&params());
+
+        let frame = decoder.next_frame::<TestMsg>().unwrap().unwrap();
+        assert_eq!(frame.msg_type, TestMsg::Params);
+        assert_eq!(decoder.stats.discarded_bytes, garbage.len() as u64);
+    }
+
+    #[test]
+    fn joins_split_frames() {
+        let frame = params();
+        let mut decoder = FrameDecoder::new();
+
+        for byte in &frame[..frame.len() - 1] {
+            decoder.push(&[*byte]);
+            assert!(decoder.next_frame::<TestMsg>().is_none());
+        }
+
+        decoder.push(&frame[frame.len() - 1..]);
+        let frame = decoder.next_frame::<TestMsg>().unwrap().unwrap();
+        assert_eq!(frame.payload, [1, 2, 3, 4]);
+        assert_eq!(decoder.stats.discarded_bytes, 0);
+    }
+
+    #[test]
+    fn bad_crc_drops_only_start_byte() {
+        let mut bad = params();
+        *bad.last_mut().unwrap() ^= 0xff;
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&bad);
+        decoder.push(&params());
+
+        assert_eq!(
+            decoder.next_frame::<TestMsg>().unwrap().unwrap_err(),
+            DecodeError::BadCrc(TestMsg::Params.val())
+        );
+        assert_eq!(decoder.pending(), bad.len() * 2 - 1);
+        assert_eq!(decoder.stats.bad_crc, 1);
+
+        // The rest of the bad frame is skipped while resyncing.
+        let frame = decoder.next_frame::<TestMsg>().unwrap().unwrap();
+        assert_eq!(frame.payload, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn unknown_type() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&[MSG_START, DEVICE_CODE_PC, UNKNOWN_TYPE]);
+        decoder.push(&params());
+
+        assert_eq!(
+            decoder.next_frame::<TestMsg>().unwrap().unwrap_err(),
+            DecodeError::UnknownType(UNKNOWN_TYPE)
+        );
+        assert_eq!(decoder.stats.unknown_type, 1);
+
+        let frame = decoder.next_frame::<TestMsg>().unwrap().unwrap();
+        assert_eq!(frame.msg_type, TestMsg::Params);
+    }
+
+    #[test]
+    fn telemetry_sized_by_length_byte() {
+        let len = 5;
+        let mut payload = vec![0; len + MAVLINK_SIZE];
+        payload[1] = len as u8;
+        let frame = encode_frame_from(DEVICE_CODE_PC, TestMsg::Telemetry, &payload).unwrap();
+
+        let mut decoder = FrameDecoder::new();
+        // Without the length byte, we can't know the size.
+        decoder.push(&frame[..PAYLOAD_START_I + 1]);
+        assert!(decoder.next_frame::<TestMsg>().is_none());
+
+        decoder.push(&frame[PAYLOAD_START_I + 1..frame.len() - 1]);
+        assert!(decoder.next_frame::<TestMsg>().is_none());
+
+        decoder.push(&frame[frame.len() - 1..]);
+        let decoded = decoder.next_frame::<TestMsg>().unwrap().unwrap();
+        assert_eq!(decoded.msg_type, TestMsg::Telemetry);
+        assert_eq!(decoded.payload, payload);
+    }
+
+    #[test]
+    fn reset_counts_truncated() {
+        let frame = params();
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&frame[..2]);
+
+        assert_eq!(decoder.reset(), Some(DecodeError::Truncated(2)));
+        assert_eq!(decoder.reset(), None);
+        assert_eq!(decoder.stats.truncated, 1);
+    }
+}
@@ -0,0 +1,80 @@
+//! Keeps the most recent frames in each direction, for display; see `ui::diagnostics_window` and
+//! `ui::Terminal`. Enable it by setting `StateCommon::frame_log`.
+
+use std::{collections::VecDeque, time::Instant};
+
+use crate::capture::Direction;
+
+pub const DEFAULT_FRAME_LOG_SIZE: usize = 100;
+
+#[derive(Clone, Debug)]
+pub struct LoggedFrame {
+    pub time: Instant,
+    /// The whole frame, including header and CRC. For `FrameLog::raw_received`, whatever was
+    /// read at once.
+    pub bytes: Vec<u8>,
+}
+
+impl LoggedFrame {
+    /// The message type byte.
+    pub fn msg_type(&self) -> Option<u8> {
+        self.bytes.get(2).copied()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FrameLog {
+    /// Frames kept per direction; older ones are dropped.
+    pub capacity: usize,
+    pub sent: VecDeque<LoggedFrame>,
+    pub received: VecDeque<LoggedFrame>,
+    /// Bytes as read from the port, before decoding; including any that aren't part of a valid
+    /// frame. With the I/O worker, it forwards these; see `WorkerEvent::Raw`.
+    pub raw_received: VecDeque<LoggedFrame>,
+}
+
+impl Default for FrameLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_FRAME_LOG_SIZE)
+    }
+}
+
+impl FrameLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            sent: VecDeque::with_capacity(capacity),
+            received: VecDeque::with_capacity(capacity),
+            raw_received: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn record(&mut self, direction: Direction, bytes: &[u8], time: Instant) {
+        let frames = match direction {
+            Direction::Tx => &mut self.sent,
+            Direction::Rx => &mut self.received,
+        };
+        push(frames, self.capacity, bytes, time);
+    }
+
+    pub fn record_raw(&mut self, bytes: &[u8], time: Instant) {
+        push(&mut self.raw_received, self.capacity, bytes, time);
+    }
+
+    pub fn clear(&mut self) {
+        self.sent.clear();
+        self.received.clear();
+        self.raw_received.clear();
+    }
+}
+
+fn push(frames: &mut VecDeque<LoggedFrame>, capacity: usize, bytes: &[u8], time: Instant) {
+    while frames.len() >= capacity.max(1) {
+        frames.pop_front();
+    }
+
+    frames.push_back(LoggedFrame {
+        time,
+        bytes: bytes.to_vec(),
+    });
+}
@@ -0,0 +1,38 @@
+//! Periodically pings the device, so we notice when it stops responding even if the app isn't
+//! otherwise querying it. Set `StateCommon::heartbeat`; `tick` sends the pings, and the device's
+//! replies are returned by `receive` like any other frame.
+
+use std::time::{Duration, Instant};
+
+use anyleaf_usb::MessageType;
+
+use crate::{encode_frame, Error};
+
+/// A reasonable interval; well inside the default `SerialConfig::disconnect_timeout`.
+pub const DEFAULT_HEARTBEAT_INTERVAL_MS: u64 = 250;
+
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    /// The ping, already framed; this lets `StateCommon` send it without knowing its type.
+    pub frame: Vec<u8>,
+    /// How long the link can be quiet before we ping. Any message the app sends counts.
+    pub interval: Duration,
+}
+
+impl Heartbeat {
+    pub fn new<T: MessageType>(
+        msg_type: T,
+        payload: &[u8],
+        interval: Duration,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            frame: encode_frame(msg_type, payload)?,
+            interval,
+        })
+    }
+
+    /// True if nothing's been sent for `interval`.
+    pub fn is_due(&self, last_query: Instant, now: Instant) -> bool {
+        now.saturating_duration_since(last_query) >= self.interval
+    }
+}
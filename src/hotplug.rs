@@ -0,0 +1,102 @@
+//! Detects our device being plugged in and unplugged, by polling the port list on a background
+//! thread. (udev would avoid polling on Linux, but needs libudev at build time, and isn't
+//! available on other platforms.) Start it with `StateCommon::start_hotplug`.
+
+use std::{
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use eframe::egui;
+
+use crate::{discover, discovery::MatchRule, PortCandidate};
+
+/// How often we check the port list. Enumeration is cheap, but not free on Windows.
+pub const HOTPLUG_INTERVAL_MS: u64 = 500;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum HotplugEvent {
+    /// A port matching our rules appeared.
+    Connected(PortCandidate),
+    /// A port matching our rules went away.
+    Disconnected(PortCandidate),
+}
+
+/// A handle to the polling thread. Dropping it stops the thread.
+pub struct HotplugMonitor {
+    pub event_rx: Receiver<HotplugEvent>,
+    /// Dropped to stop the thread; this wakes it immediately, instead of after `interval`.
+    stop_tx: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl HotplugMonitor {
+    /// Start polling. Ports present at startup are reported as `Connected`. If `ctx` is passed,
+    /// egui is asked to repaint on each event.
+    pub fn spawn(
+        match_rules: Vec<MatchRule>,
+        interval: Duration,
+        ctx: Option<egui::Context>,
+    ) -> Self {
+        let (event_tx, event_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+
+        let handle = thread::spawn(move || {
+            let mut present: Vec<PortCandidate> = Vec::new();
+
+            loop {
+                // Enumeration can fail transiently; try again next time.
+                if let Ok(ports) = discover() {
+                    let ports: Vec<_> = ports
+                        .into_iter()
+                        .filter(|port| match_rules.iter().any(|rule| rule.matches(port)))
+                        .collect();
+
+                    let mut events = Vec::new();
+                    for port in &present {
+                        if !ports.iter().any(|p| p.port_name == port.port_name) {
+                            events.push(HotplugEvent::Disconnected(port.clone()));
+                        }
+                    }
+                    for port in &ports {
+                        if !present.iter().any(|p| p.port_name == port.port_name) {
+                            events.push(HotplugEvent::Connected(port.clone()));
+                        }
+                    }
+
+                    for event in events {
+                        if event_tx.send(event).is_err() {
+                            return;
+                        }
+                        if let Some(ctx) = &ctx {
+                            ctx.request_repaint();
+                        }
+                    }
+
+                    present = ports;
+                }
+
+                // We're only woken early when the handle's dropped.
+                if stop_rx.recv_timeout(interval) != Err(RecvTimeoutError::Timeout) {
+                    return;
+                }
+            }
+        });
+
+        Self {
+            event_rx,
+            stop_tx: Some(stop_tx),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for HotplugMonitor {
+    fn drop(&mut self) {
+        self.stop_tx = None;
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
@@ -4,19 +4,68 @@
 //! See the separate module (anyleaf_usb) for code we share
 //! between PC and firmware.
 
+#[cfg(feature = "async")]
+pub mod async_serial;
+pub mod capture;
+pub mod config;
+pub mod discovery;
+pub mod error;
+pub mod frame;
+pub mod frame_log;
+pub mod heartbeat;
+pub mod hotplug;
+pub mod manager;
+pub mod mock;
+pub mod pcapng;
+#[cfg(unix)]
+pub mod pty;
+pub mod slcan;
+pub mod stats;
+pub mod transport;
+pub mod ui;
+pub mod worker;
+
 use std::{
-    io::{self, Write},
-    time::{Duration, Instant},
+    collections::VecDeque,
+    io, mem,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyleaf_usb::{
     self, MessageType, MsgType, DEVICE_CODE_PC, MAVLINK_SIZE, MSG_START, PAYLOAD_START_I,
 };
 use eframe::egui::{self, Color32};
-use serialport::{self, SerialPort, SerialPortType};
+use serialport::{self, SerialPort};
+
+#[cfg(feature = "async")]
+pub use crate::async_serial::AsyncSerialInterface;
+#[cfg(unix)]
+pub use crate::pty::VirtualDevice;
+pub use crate::{
+    capture::{Capture, CaptureTransport, ReplayTransport, SharedCapture},
+    config::SerialConfig,
+    discovery::{discover, MatchRule, PortCandidate},
+    error::Error,
+    frame::{DecodeError, DecodeStats, Frame, FrameDecoder},
+    frame_log::FrameLog,
+    heartbeat::Heartbeat,
+    hotplug::{HotplugEvent, HotplugMonitor},
+    manager::DeviceManager,
+    mock::MockDevice,
+    pcapng::{PcapngWriter, SharedPcapng},
+    slcan::{Bitrate, CanFrame, SlcanTransport},
+    stats::LinkStats,
+    transport::{Loopback, Transport},
+    ui::{PortPicker, Terminal},
+    worker::{Worker, WorkerEvent},
+};
 
 const SLCAN_PRODUCT_KEYWORD: &str = "slcan";
 
+/// Defaults for `SerialConfig`.
 const BAUD: u32 = 460_800;
 
 const TIMEOUT_MILIS: u64 = 10;
@@ -25,10 +74,28 @@ pub const DISCONNECTED_TIMEOUT_MS: u64 = 1_000;
 
 pub type Port = Box<dyn SerialPort>;
 
-#[derive(Clone, Copy, PartialEq)]
+/// How often we retry opening the port while not connected, when driven by `StateCommon::tick`.
+const RETRY_INTERVAL_MS: u64 = 1_000;
+
+/// Hotplug events kept for `StateCommon::take_hotplug_events`; older ones are dropped.
+const MAX_HOTPLUG_EVENTS: usize = 64;
+
+#[derive(Clone, PartialEq, Debug)]
 pub enum ConnectionStatus {
     NotConnected,
+    /// Enumerating ports, looking for our device.
+    Searching,
+    /// We found the device, and are opening its port.
+    Opening,
     Connected,
+    /// The port is open, but the device hasn't responded within
+    /// `SerialConfig::disconnect_timeout`.
+    Stale,
+    /// We found the device, but don't have permission to open it. (eg not in the `dialout` group)
+    PermissionDenied,
+    /// We found the device, but another program has its port open.
+    PortBusy,
+    Error(String),
 }
 
 impl Default for ConnectionStatus {
@@ -41,16 +108,30 @@ impl ConnectionStatus {
     pub fn as_str(&self) -> &str {
         match self {
             Self::NotConnected => "Not connected",
+            Self::Searching => "Searching",
+            Self::Opening => "Opening",
             Self::Connected => "Connected",
+            Self::Stale => "Not responding",
+            Self::PermissionDenied => "Permission denied",
+            Self::PortBusy => "Port busy",
+            Self::Error(reason) => reason,
         }
     }
 
     pub fn as_color(&self) -> Color32 {
         match self {
             Self::NotConnected => Color32::YELLOW,
+            Self::Searching | Self::Opening => Color32::LIGHT_BLUE,
             Self::Connected => Color32::LIGHT_GREEN,
+            Self::Stale => Color32::ORANGE,
+            Self::PermissionDenied | Self::PortBusy | Self::Error(_) => Color32::LIGHT_RED,
         }
     }
+
+    /// The port is open, whether or not the device is responding.
+    pub fn is_open(&self) -> bool {
+        matches!(self, Self::Connected | Self::Stale)
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -59,6 +140,15 @@ pub enum ConnectionType {
     Can,
 }
 
+impl ConnectionType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Usb => "USB",
+            Self::Can => "CAN",
+        }
+    }
+}
+
 impl Default for ConnectionType {
     fn default() -> Self {
         Self::Usb
@@ -68,124 +158,920 @@ impl Default for ConnectionType {
 /// This mirrors that in the Python driver
 #[derive(Default)]
 pub struct SerialInterface {
-    /// compatibity, since Windows and Linux use different types; the `serial_port` docs are build
-    /// for Linux, and don't show the Windows type. (ie `TTYPort vs COMPort`)
-    pub serial_port: Option<Port>,
+    /// A trait object for compatibility with serial ports on both Windows and Linux, SLCAN
+    /// adapters, and in-memory links. (The `serial_port` docs are built for Linux, and don't show
+    /// the Windows type. ie `TTYPort vs COMPort`)
+    pub transport: Option<Box<dyn Transport>>,
     pub connection_type: ConnectionType,
+    /// eg `/dev/ttyACM0`. `None` for transports that weren't opened by name.
+    pub port_name: Option<String>,
 }
 
 impl SerialInterface {
-    /// Create a new interface; either USB or CAN, depending on which we find first.
-    pub(crate) fn connect(usb_serial_number: &str) -> Self {
-        let mut connection_type = ConnectionType::Usb;
-
-        let ports = serialport::available_ports();
-        if ports.is_err() {
-            return Self::default();
-        }
-
-        for port_info in &ports.unwrap() {
-            let mut correct_port = false;
-            if let SerialPortType::UsbPort(info) = &port_info.port_type {
-                // Indicates a USB connection.
-                if let Some(sn) = &info.serial_number {
-                    if sn == usb_serial_number {
-                        correct_port = true;
-                    }
+    /// Wrap an existing transport; eg a `Loopback` for testing without hardware.
+    pub fn from_transport(transport: Box<dyn Transport>, connection_type: ConnectionType) -> Self {
+        Self {
+            transport: Some(transport),
+            connection_type,
+            port_name: None,
+        }
+    }
 
-                    // Indicates a (Serial) CAN connection.
-                } else if let Some(product_name) = &info.product {
-                    if product_name.to_lowercase().contains(SLCAN_PRODUCT_KEYWORD) {
-                        connection_type = ConnectionType::Can;
-                        correct_port = true;
-                    }
-                }
-            }
+    /// Create a new interface; either USB or CAN, depending on which rule matches first. If
+    /// `port_override` is set, we open that port instead of searching.
+    pub(crate) fn connect(
+        match_rules: &[MatchRule],
+        port_override: Option<&str>,
+        config: &SerialConfig,
+    ) -> Result<Self, Error> {
+        if let Some(port_name) = port_override {
+            return Self::open(port_name, discovery::connection_type_of(port_name), config);
+        }
 
-            if !correct_port {
-                continue;
-            }
+        match discovery::find_matching(&discover()?, match_rules) {
+            Some((port_name, connection_type)) => Self::open(&port_name, connection_type, config),
+            None => Err(Error::NoDevice),
+        }
+    }
 
-            match serialport::new(&port_info.port_name, BAUD)
-                .timeout(Duration::from_millis(TIMEOUT_MILIS))
-                .open()
-            {
-                Ok(port) => {
-                    return Self {
-                        serial_port: Some(port),
-                        connection_type,
-                    };
-                }
+    /// Find the port name of our device. Matches USB devices by serial number, and SLCAN adapters
+    /// by product name, preferring USB. If several ports match, the first is used; use `discover`
+    /// to choose instead. For other criteria, see `MatchRule`.
+    pub fn find_port(usb_serial_number: &str) -> Option<(String, ConnectionType)> {
+        discovery::find_matching(
+            &discover().ok()?,
+            &discovery::default_rules(usb_serial_number),
+        )
+    }
 
-                Err(serialport::Error { kind, description }) => {
-                    match kind {
-                        // serialport::ErrorKind::Io(io_kind) => {
-                        //     println!("IO error openin the port");
-                        // }
-                        serialport::ErrorKind::NoDevice => {
-                            // todo: Probably still getting this, but it seems to not
-                            // todo be a dealbreaker. Eventually deal with it.
-                            // println!("No device: {:?}", description);
-                        }
-                        _ => {
-                            println!("Error opening the port: {:?} - {:?}", kind, description);
-                        }
-                    }
-                }
-            }
-            break;
+    /// Open a specific port.
+    pub fn open(
+        port_name: &str,
+        connection_type: ConnectionType,
+        config: &SerialConfig,
+    ) -> Result<Self, Error> {
+        let mut port = config
+            .builder(port_name)
+            .open()
+            .map_err(|e| Error::from_serial(e, port_name))?;
+
+        if let Some(level) = config.dtr {
+            port.write_data_terminal_ready(level)
+                .map_err(|e| Error::from_serial(e, port_name))?;
+        }
+        if let Some(level) = config.rts {
+            port.write_request_to_send(level)
+                .map_err(|e| Error::from_serial(e, port_name))?;
         }
 
-        Self::default()
+        let transport: Box<dyn Transport> = match connection_type {
+            ConnectionType::Usb => Box::new(port),
+            // Our messages are segmented into CAN frames by the SLCAN transport.
+            ConnectionType::Can => {
+                let mut transport = SlcanTransport::open(port, config.can_bitrate, false)?;
+                transport.tx_id = config.can_tx_id;
+                transport.rx_id = config.can_rx_id;
+                transport.extended = config.can_extended;
+                Box::new(transport)
+            }
+        };
+
+        let mut result = Self::from_transport(transport, connection_type);
+        result.port_name = Some(port_name.to_owned());
+        Ok(result)
+    }
+}
+
+/// Why `StateCommon` last re-opened the port.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ReconnectReason {
+    /// There was no open port; eg on startup, or the device wasn't found last time.
+    NotConnected,
+    /// A read or write on the port failed.
+    IoError(io::ErrorKind),
+    /// We've been querying, but haven't had a response within
+    /// `SerialConfig::disconnect_timeout`.
+    ResponseTimeout,
+}
+
+impl ReconnectReason {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::NotConnected => "Not connected",
+            Self::IoError(_) => "I/O error",
+            Self::ResponseTimeout => "Response timeout",
+        }
     }
 }
 
 /// Use this state as a field of application-specific state.
 pub struct StateCommon {
     pub usb_serial_number: String,
+    /// If set, open this port (eg `/dev/ttyACM0`, `COM3`, or a `VirtualDevice` path) instead of
+    /// searching by serial number.
+    pub port_override: Option<String>,
+    /// Used when opening the port, and to detect an unresponsive device. Changes take effect on
+    /// the next reconnect.
+    pub config: SerialConfig,
+    /// How to find our device, in priority order. If empty, we match by `usb_serial_number`,
+    /// then look for an SLCAN adapter.
+    pub match_rules: Vec<MatchRule>,
+    /// If set, `tick` pings the device when the link is quiet; see the `heartbeat` module. If
+    /// using the I/O worker, set this before `start_worker`.
+    pub heartbeat: Option<Heartbeat>,
     pub connection_status: ConnectionStatus,
+    /// With the I/O worker, there's no transport here; the port name and connection type are
+    /// as reported by the worker.
     pub interface: SerialInterface,
+    /// When we last sent something. Updated by all sends, including heartbeats.
     pub last_query: Instant,
     /// Used for determining if we're still connected, and getting updates from the FC.
     pub last_response: Instant,
+    /// Buffers received data between `receive` calls.
+    pub decoder: FrameDecoder,
+    /// If set, a background thread owns the port, and this state is a handle to it.
+    pub worker: Option<Worker>,
+    /// Frames received from the worker, but not yet returned by `receive`.
+    worker_frames: VecDeque<Frame<u8>>,
+    /// Set when an operation on the port fails; the port is re-opened by `tick`, or by `get_port`
+    /// once a retry is due.
+    pub io_error: Option<io::ErrorKind>,
+    /// Why we last reconnected, and when.
+    pub last_reconnect: Option<(ReconnectReason, Instant)>,
+    pub reconnect_count: u32,
+    /// Traffic and error counts for the link; see the `stats` module.
+    pub stats: LinkStats,
+    /// If set, recent frames are kept for display. Frames the I/O worker sends itself (ie
+    /// heartbeats) aren't included.
+    pub frame_log: Option<FrameLog>,
+    /// The port found while `Searching`, to open in the `Opening` state.
+    pending_port: Option<(String, ConnectionType)>,
+    last_connect_attempt: Option<Instant>,
+    /// The transport was passed in with `attach`, so we can't re-open it.
+    attached: bool,
+    /// Set by `disconnect`; we don't reconnect automatically until `connect` is called.
+    disconnected: bool,
+    /// If set, all traffic is recorded; see `start_capture`.
+    pub capture: Option<SharedCapture>,
+    /// If set, frames are logged for Wireshark; see `start_pcap`.
+    pub pcap: Option<SharedPcapng>,
+    hotplug: Option<HotplugMonitor>,
+    /// Hotplug events not yet taken by the app.
+    hotplug_events: VecDeque<HotplugEvent>,
 }
 
 impl StateCommon {
-    pub fn new(usb_serial_number: &str) -> Self {
+    /// Use `SerialConfig::default()` for our USB devices.
+    pub fn new(usb_serial_number: &str, config: SerialConfig) -> Self {
         Self {
             usb_serial_number: usb_serial_number.to_owned(),
+            port_override: None,
+            config,
+            match_rules: Vec::new(),
+            heartbeat: None,
             connection_status: Default::default(),
             interface: Default::default(),
             last_query: Instant::now(),
             last_response: Instant::now(),
+            decoder: Default::default(),
+            worker: None,
+            worker_frames: VecDeque::new(),
+            io_error: None,
+            last_reconnect: None,
+            reconnect_count: 0,
+            stats: Default::default(),
+            frame_log: None,
+            pending_port: None,
+            last_connect_attempt: None,
+            attached: false,
+            disconnected: false,
+            capture: None,
+            pcap: None,
+            hotplug: None,
+            hotplug_events: VecDeque::new(),
+        }
+    }
+
+    /// We use this to re-initialized the serial interface. With the I/O worker, this only queues
+    /// the attempt; the result arrives as a status change.
+    pub fn connect(&mut self) -> Result<(), Error> {
+        self.disconnected = false;
+
+        if let Some(worker) = &self.worker {
+            return worker.connect(self.port_override.clone());
+        }
+
+        self.last_connect_attempt = Some(Instant::now());
+        self.set_interface(SerialInterface::connect(
+            &self.active_match_rules(),
+            self.port_override.as_deref(),
+            &self.config,
+        ))
+    }
+
+    /// Connect to a specific port, eg one chosen from `discover`, instead of searching by serial
+    /// number. This persists across reconnects; set `port_override` to `None` to resume
+    /// searching.
+    pub fn connect_to_port(&mut self, port_name: &str) -> Result<(), Error> {
+        self.port_override = Some(port_name.to_owned());
+        self.connect()
+    }
+
+    /// `match_rules`, or the defaults if none are set.
+    pub fn active_match_rules(&self) -> Vec<MatchRule> {
+        if self.match_rules.is_empty() {
+            discovery::default_rules(&self.usb_serial_number)
+        } else {
+            self.match_rules.clone()
+        }
+    }
+
+    /// True if `disconnect` was called, and `connect` hasn't been since.
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected
+    }
+
+    /// Close the port. We don't reconnect until `connect` is called.
+    pub fn disconnect(&mut self) {
+        self.disconnected = true;
+        self.connection_status = ConnectionStatus::NotConnected;
+
+        if let Some(worker) = &self.worker {
+            // If the worker's gone, there's no port to close.
+            worker.disconnect().ok();
+            return;
+        }
+
+        self.interface = Default::default();
+        self.attached = false;
+        self.pending_port = None;
+        self.io_error = None;
+        self.decoder.reset();
+    }
+
+    fn set_interface(&mut self, interface: Result<SerialInterface, Error>) -> Result<(), Error> {
+        // Any partial frame belongs to the old connection.
+        self.decoder.reset();
+        self.attached = false;
+
+        match interface {
+            Ok(interface) => {
+                self.interface = interface;
+                self.wrap_capture();
+                self.apply_pcap();
+                self.connection_status = ConnectionStatus::Connected;
+                Ok(())
+            }
+            Err(e) => {
+                self.interface = Default::default();
+                self.connection_status = (&e).into();
+                Err(e)
+            }
+        }
+    }
+
+    /// Record everything sent and received to a capture file, including across reconnects.
+    /// See the `capture` module for the format, and `ReplayTransport` to play it back.
+    /// If using the I/O worker, call this before `start_worker`.
+    pub fn start_capture(&mut self, path: &Path) -> Result<(), Error> {
+        self.stop_capture()?;
+
+        self.capture = Some(Arc::new(Mutex::new(Capture::create(path)?)));
+        self.wrap_capture();
+
+        Ok(())
+    }
+
+    /// Stop recording, and flush the capture file.
+    pub fn stop_capture(&mut self) -> Result<(), Error> {
+        if let Some(capture) = self.capture.take() {
+            // The transport may still hold a reference; it stops recording once this is closed.
+            capture.lock().unwrap().stop()?;
+        }
+        Ok(())
+    }
+
+    /// Wrap the current transport so its traffic is recorded, if we're capturing.
+    fn wrap_capture(&mut self) {
+        let Some(capture) = &self.capture else {
+            return;
+        };
+
+        if let Some(transport) = self.interface.transport.take() {
+            self.interface.transport =
+                Some(Box::new(CaptureTransport::new(transport, capture.clone())));
         }
     }
 
-    /// We use this to re-initialized the serial interface.
-    pub fn connect(&mut self) {
-        self.interface = SerialInterface::connect(&self.usb_serial_number);
+    /// Log frames to a pcapng file, for viewing in Wireshark. Over USB, each of our frames is a
+    /// packet. Over CAN, each CAN frame is a packet, in SocketCAN format. The link type is set by
+    /// the current connection type, so call this once connected. If using the I/O worker, call
+    /// this before `start_worker`.
+    pub fn start_pcap(&mut self, path: &Path) -> Result<(), Error> {
+        self.stop_pcap()?;
+
+        let link_type = pcap_link_type(self.interface.connection_type);
+        self.pcap = Some(Arc::new(Mutex::new(PcapngWriter::create(path, link_type)?)));
+        self.apply_pcap();
+
+        Ok(())
     }
 
-    /// Get the serial port; handles unwrapping.
-    pub fn get_port(&mut self) -> Result<&mut Port, io::Error> {
-        self.connect();
-        // if self.interface.serial_port.is_none() {
-        //     self.connect();
-        // }
+    /// Stop logging, and flush the pcapng file.
+    pub fn stop_pcap(&mut self) -> Result<(), Error> {
+        if let Some(transport) = &mut self.interface.transport {
+            transport.set_pcap(None);
+        }
 
-        match self.interface.serial_port.as_mut() {
-            Some(p) => Ok(p),
-            None => Err(io::Error::new(
-                io::ErrorKind::NotConnected,
-                "No device connected",
-            )),
+        if let Some(pcap) = self.pcap.take() {
+            pcap.lock().unwrap().flush()?;
         }
+        Ok(())
+    }
+
+    /// Pass the pcapng writer to the transport, if it logs at the link level.
+    fn apply_pcap(&mut self) {
+        let Some(transport) = &mut self.interface.transport else {
+            return;
+        };
+
+        // USB frames are logged by us, as they're decoded; not by the transport.
+        let pcap = self.pcap.clone().filter(|pcap| {
+            self.interface.connection_type == ConnectionType::Can
+                && pcap.lock().unwrap().link_type == pcapng::LINKTYPE_CAN_SOCKETCAN
+        });
+        transport.set_pcap(pcap);
+    }
+
+    /// Log one of our frames, if logging them over USB.
+    fn log_pcap(&self, direction: capture::Direction, frame: &[u8]) {
+        let Some(pcap) = &self.pcap else {
+            return;
+        };
+
+        let mut pcap = pcap.lock().unwrap();
+        if pcap.link_type == pcapng::LINKTYPE_USER0 {
+            pcap.write_packet(SystemTime::now(), direction, frame).ok();
+        }
+    }
+
+    /// Watch for our device being plugged in or unplugged; ie ports matching
+    /// `active_match_rules`. When it's plugged in, we connect immediately, instead of on the next
+    /// periodic retry. When our port goes away, we close it and start searching. Events are
+    /// processed by `tick`, or by the I/O worker if started after this. If `ctx` is passed, egui
+    /// is asked to repaint on each event, so `tick` runs without other GUI activity.
+    pub fn start_hotplug(&mut self, ctx: Option<egui::Context>) {
+        self.hotplug = Some(HotplugMonitor::spawn(
+            self.active_match_rules(),
+            Duration::from_millis(hotplug::HOTPLUG_INTERVAL_MS),
+            ctx,
+        ));
+    }
+
+    pub fn stop_hotplug(&mut self) {
+        self.hotplug = None;
+    }
+
+    /// Hotplug events since the last call, oldest first. Handling them is optional; the
+    /// connection is managed either way.
+    pub fn take_hotplug_events(&mut self) -> Vec<HotplugEvent> {
+        self.hotplug_events.drain(..).collect()
+    }
+
+    /// Act on events from the hotplug monitor.
+    fn poll_hotplug(&mut self) {
+        let Some(hotplug) = &self.hotplug else {
+            return;
+        };
+        let events: Vec<_> = hotplug.event_rx.try_iter().collect();
+
+        for event in events {
+            match &event {
+                HotplugEvent::Connected(_) => {
+                    let idle = !matches!(
+                        self.connection_status,
+                        ConnectionStatus::Searching | ConnectionStatus::Opening
+                    ) && !self.connection_status.is_open();
+
+                    if idle && !self.disconnected && !self.attached {
+                        self.connection_status = ConnectionStatus::Searching;
+                    }
+                }
+                HotplugEvent::Disconnected(port) => {
+                    if self.interface.port_name.as_ref() == Some(&port.port_name) {
+                        // `tick` reconnects from here, as for a failed read.
+                        self.io_error = Some(io::ErrorKind::NotConnected);
+                    }
+                }
+            }
+            push_hotplug_event(&mut self.hotplug_events, event);
+        }
+    }
+
+    /// Use an existing transport instead of searching for the device; eg a `Loopback` connected
+    /// to a `MockDevice`. Since we can't re-open it, reconnecting only clears error state.
+    pub fn attach(&mut self, transport: Box<dyn Transport>, connection_type: ConnectionType) {
+        self.set_interface(Ok(SerialInterface::from_transport(
+            transport,
+            connection_type,
+        )))
+        .ok();
+        self.attached = true;
+        self.io_error = None;
+        self.last_response = Instant::now();
+    }
+
+    /// Move port handling to a background thread, so reads and port enumeration don't stall the
+    /// GUI. `T` is used to decode received frames. If `ctx` is passed, egui is asked to repaint
+    /// when a frame or status change arrives. After this, `send_cmd`, `send_payload`, `receive`
+    /// and `tick` go through the thread, and `get_port` is unavailable. A transport passed to
+    /// `attach` is handed to the worker, and closed when it stops.
+    pub fn start_worker<T: MessageType + TryFrom<u8> + 'static>(
+        &mut self,
+        ctx: Option<egui::Context>,
+    ) {
+        let interface = mem::take(&mut self.interface);
+        let attached = mem::take(&mut self.attached);
+
+        // The worker handles the connection with its own state, using our settings.
+        let mut state = StateCommon::new(&self.usb_serial_number, self.config.clone());
+        state.port_override = self.port_override.clone();
+        state.match_rules = self.match_rules.clone();
+        state.heartbeat = self.heartbeat.clone();
+        state.capture = self.capture.clone();
+        state.pcap = self.pcap.clone();
+        // The worker processes hotplug events, and passes them back to us.
+        state.hotplug = self.hotplug.take();
+        // The worker collects raw reads in its log, and passes them back to us for ours.
+        state.frame_log = Some(FrameLog::default());
+
+        if attached {
+            // We can't re-open it, so the worker takes it as-is; it's already wrapped for
+            // capture and pcap. The worker only reports changes, so keep its connection type.
+            self.interface.connection_type = interface.connection_type;
+            state.interface = interface;
+            state.attached = true;
+            state.decoder = mem::take(&mut self.decoder);
+            state.connection_status = ConnectionStatus::Connected;
+        } else {
+            // Release the port so the worker can open it.
+            drop(interface);
+            self.decoder.reset();
+        }
+
+        // The worker reports changes from its initial status.
+        self.connection_status = state.connection_status.clone();
+        self.worker = Some(Worker::spawn::<T>(state, ctx));
+    }
+
+    /// Stop the background thread, and return to handling the port directly. This stops hotplug
+    /// monitoring, if it was passed to the worker; call `start_hotplug` again to resume it.
+    pub fn stop_worker(&mut self) {
+        // Dropping the handle joins the thread.
+        self.worker = None;
+        self.worker_frames.clear();
+        self.interface = Default::default();
+        self.connection_status = ConnectionStatus::NotConnected;
+    }
+
+    /// Process everything the worker has sent us. Returns the first error reported, if any.
+    fn poll_worker(&mut self) -> Result<(), Error> {
+        let Some(worker) = &self.worker else {
+            return Ok(());
+        };
+
+        let mut result = Ok(());
+
+        for event in worker.event_rx.try_iter() {
+            match event {
+                WorkerEvent::Frame(frame) => {
+                    self.last_response = Instant::now();
+                    self.worker_frames.push_back(frame);
+                }
+                WorkerEvent::Raw(bytes) => {
+                    if let Some(log) = &mut self.frame_log {
+                        log.record_raw(&bytes, Instant::now());
+                    }
+                }
+                WorkerEvent::Status(status) => self.connection_status = status,
+                WorkerEvent::Port {
+                    port_name,
+                    connection_type,
+                } => {
+                    self.interface.port_name = port_name;
+                    self.interface.connection_type = connection_type;
+                }
+                // Request latency and timeouts are measured here, not by the worker.
+                WorkerEvent::Stats(mut stats) => {
+                    stats.latency = mem::take(&mut self.stats.latency);
+                    stats.timeouts = self.stats.timeouts;
+                    self.stats = stats;
+                }
+                WorkerEvent::Hotplug(event) => push_hotplug_event(&mut self.hotplug_events, event),
+                WorkerEvent::Error(e) => {
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Drive connection state transitions. Call this regularly, eg once per GUI frame. This
+    /// searches for and opens the port (one step per call, so the GUI can show progress), and
+    /// flags the connection as stale if the device stops responding.
+    pub fn tick(&mut self, now: Instant) {
+        if self.worker.is_some() {
+            // The worker runs its own state machine, and reports status changes. Errors are
+            // reflected in the status, or returned from `receive`.
+            self.poll_worker().ok();
+            return;
+        }
+
+        self.poll_hotplug();
+        self.stats.update_rates(now);
+
+        if self.disconnected {
+            return;
+        }
+
+        match self.connection_status {
+            ConnectionStatus::Searching => {
+                self.last_connect_attempt = Some(now);
+                let port = match &self.port_override {
+                    Some(port_name) => {
+                        Some((port_name.clone(), discovery::connection_type_of(port_name)))
+                    }
+                    None => discover().ok().and_then(|ports| {
+                        discovery::find_matching(&ports, &self.active_match_rules())
+                    }),
+                };
+
+                match port {
+                    Some(port) => {
+                        self.pending_port = Some(port);
+                        self.connection_status = ConnectionStatus::Opening;
+                    }
+                    None => self.connection_status = ConnectionStatus::NotConnected,
+                }
+            }
+            ConnectionStatus::Opening => match self.pending_port.take() {
+                Some((port_name, connection_type)) => {
+                    // The status reflects any error.
+                    self.set_interface(SerialInterface::open(
+                        &port_name,
+                        connection_type,
+                        &self.config,
+                    ))
+                    .ok();
+                    // Give the device a full timeout period to respond.
+                    self.last_response = now;
+                }
+                None => self.connection_status = ConnectionStatus::Searching,
+            },
+            ConnectionStatus::Connected | ConnectionStatus::Stale => {
+                if self.attached && self.io_error.is_some() {
+                    // We can't re-open an attached transport; clear the error, and carry on.
+                    let reason = self.reconnect_reason(now).unwrap();
+                    self.reconnect(reason).ok();
+                } else if self.interface.transport.is_none() || self.io_error.is_some() {
+                    let reason = self.reconnect_reason(now).unwrap();
+                    self.interface = Default::default();
+                    self.io_error = None;
+                    self.last_reconnect = Some((reason, now));
+                    self.count_reconnect(reason);
+                    self.connection_status = ConnectionStatus::Searching;
+                } else if self.reconnect_reason(now) == Some(ReconnectReason::ResponseTimeout) {
+                    self.connection_status = ConnectionStatus::Stale;
+                }
+                // Otherwise, the status is unchanged. Once stale, only a received frame clears it;
+                // see `receive`. (Re-opening the port resets `last_response`, but doesn't mean
+                // the device is back.)
+
+                if self.connection_status.is_open() {
+                    self.send_heartbeat(now);
+                }
+            }
+            // Not connected, or a previous attempt failed; retry periodically.
+            _ => {
+                if self.retry_due(now) {
+                    self.connection_status = ConnectionStatus::Searching;
+                }
+            }
+        }
+    }
+
+    /// Opening the port enumerates devices, which is slow; we only try once per
+    /// `RETRY_INTERVAL_MS`.
+    fn retry_due(&self, now: Instant) -> bool {
+        self.last_connect_attempt.is_none_or(|t| {
+            now.saturating_duration_since(t).as_millis() as u64 >= RETRY_INTERVAL_MS
+        })
+    }
+
+    /// Ping the device if the link's been quiet for the heartbeat interval.
+    fn send_heartbeat(&mut self, now: Instant) {
+        let Some(heartbeat) = &self.heartbeat else {
+            return;
+        };
+
+        if heartbeat.is_due(self.last_query, now) {
+            let frame = heartbeat.frame.clone();
+            // Not through `get_port`, since that re-opens the port if the device isn't
+            // responding; finding that out is what the heartbeat is for. Write errors are flagged
+            // for reconnection on the next tick.
+            if let Some(transport) = &mut self.interface.transport {
+                let result = transport.write(&frame).map_err(Error::from);
+                self.record_write(&frame, result).ok();
+            }
+        }
+    }
+
+    /// Determine if the port needs to be re-opened, and if so, why.
+    pub fn reconnect_reason(&self, now: Instant) -> Option<ReconnectReason> {
+        if self.interface.transport.is_none() {
+            return Some(ReconnectReason::NotConnected);
+        }
+
+        if let Some(kind) = self.io_error {
+            return Some(ReconnectReason::IoError(kind));
+        }
+
+        // Only treat silence as a problem if we've been asking for something.
+        if self.last_query > self.last_response
+            && now - self.last_response > self.config.disconnect_timeout
+        {
+            return Some(ReconnectReason::ResponseTimeout);
+        }
+
+        None
+    }
+
+    /// Re-open the port, recording why.
+    pub fn reconnect(&mut self, reason: ReconnectReason) -> Result<(), Error> {
+        let result = if self.attached {
+            self.decoder.reset();
+            Ok(())
+        } else {
+            // Drop the old port first, so we can re-open the same device.
+            self.interface = Default::default();
+            self.connect()
+        };
+
+        let now = Instant::now();
+        self.io_error = None;
+        // Give the device a full timeout period to respond before trying again.
+        self.last_response = now;
+        self.last_reconnect = Some((reason, now));
+        self.count_reconnect(reason);
+
+        if reason == ReconnectReason::ResponseTimeout && self.connection_status.is_open() {
+            self.connection_status = ConnectionStatus::Stale;
+        }
+
+        result
+    }
+
+    /// Count re-opening an established connection. Retries while the device is missing aren't
+    /// counted, so the count reflects a flaky link, not how long it was unplugged.
+    fn count_reconnect(&mut self, reason: ReconnectReason) {
+        if reason != ReconnectReason::NotConnected {
+            self.reconnect_count += 1;
+            self.stats.reconnects += 1;
+        }
+    }
+
+    /// Call this when an operation on the port fails, so the port is re-opened.
+    /// Read timeouts are normal with our short port timeout, so they're ignored.
+    pub fn report_io_error(&mut self, e: &io::Error) {
+        if e.kind() != io::ErrorKind::TimedOut {
+            self.io_error = Some(e.kind());
+        }
+    }
+
+    /// Get the transport to the device; handles unwrapping. Keeps the existing port open, unless
+    /// there's been an I/O error, or the device has stopped responding. Reconnection attempts are
+    /// limited to one per `RETRY_INTERVAL_MS`, so calling this every frame while the device is
+    /// unplugged is cheap; until one succeeds, this returns `Error::NoDevice`.
+    pub fn get_port(&mut self) -> Result<&mut dyn Transport, Error> {
+        if self.worker.is_some() {
+            return Err(Error::Io(io::Error::other(
+                "The port is owned by the I/O worker",
+            )));
+        }
+
+        if !self.disconnected
+            && let Some(reason) = self.reconnect_reason(Instant::now())
+            && (self.attached || self.retry_due(Instant::now()))
+        {
+            self.reconnect(reason)?;
+        }
+
+        match self.interface.transport.as_mut() {
+            Some(p) => Ok(p.as_mut()),
+            None => Err(Error::NoDevice),
+        }
+    }
+
+    /// Read what's available from the port, and return the next valid frame, if any.
+    /// Updates `last_response` when a frame is received.
+    pub fn receive<T: MessageType + TryFrom<u8>>(&mut self) -> Result<Option<Frame<T>>, Error> {
+        if self.worker.is_some() {
+            self.poll_worker()?;
+
+            return match self.worker_frames.pop_front() {
+                Some(frame) => {
+                    if let Some(log) = &mut self.frame_log {
+                        log.record(capture::Direction::Rx, &frame.to_bytes(), Instant::now());
+                    }
+                    Ok(Some(frame.typed()?))
+                }
+                None => Ok(None),
+            };
+        }
+
+        // Drain what's buffered before reading more.
+        let mut result = self.decoder.next_frame::<T>();
+
+        if result.is_none() {
+            self.get_port()?;
+
+            if let Some(port) = self.interface.transport.as_mut() {
+                match self.decoder.read_from(port) {
+                    Ok(n) => {
+                        self.stats.record_received(n, Instant::now());
+                        if n > 0
+                            && let Some(log) = &mut self.frame_log
+                        {
+                            log.record_raw(self.decoder.tail(n), Instant::now());
+                        }
+                    }
+                    Err(e) => {
+                        self.report_io_error(&e);
+                        return Err(e.into());
+                    }
+                }
+            }
+            result = self.decoder.next_frame();
+        }
+
+        self.stats.decode = self.decoder.stats;
+
+        match result {
+            Some(Ok(frame)) => {
+                self.last_response = Instant::now();
+                self.stats.frames_received += 1;
+                if self.connection_status == ConnectionStatus::Stale {
+                    self.connection_status = ConnectionStatus::Connected;
+                }
+
+                if self.pcap.is_some() || self.frame_log.is_some() {
+                    let raw = Frame {
+                        device_code: frame.device_code,
+                        msg_type: frame.msg_type.val(),
+                        payload: frame.payload.clone(),
+                    }
+                    .to_bytes();
+
+                    self.log_pcap(capture::Direction::Rx, &raw);
+                    if let Some(log) = &mut self.frame_log {
+                        log.record(capture::Direction::Rx, &raw, self.last_response);
+                    }
+                }
+
+                Ok(Some(frame))
+            }
+            Some(Err(e)) => Err(e.into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Send a payload-less command; see `send_cmd`. Flags the port for reconnection on error.
+    pub fn send_cmd<T: MessageType>(&mut self, msg_type: T) -> Result<(), Error> {
+        self.send_payload::<T, 4>(msg_type, &[])
+    }
+
+    /// Send a payload; see `send_payload`. Flags the port for reconnection on error.
+    pub fn send_payload<T: MessageType, const N: usize>(
+        &mut self,
+        msg_type: T,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        self.write_frame(&encode_payload::<T, N>(msg_type, payload)?)
+    }
+
+    /// Send a payload, sized at runtime; see `send_msg`. Flags the port for reconnection on
+    /// error.
+    pub fn send_msg<T: MessageType>(&mut self, msg_type: T, payload: &[u8]) -> Result<(), Error> {
+        self.write_frame(&encode_frame(msg_type, payload)?)
+    }
+
+    /// Send bytes as-is, without framing or checks; eg to test new firmware. Flags the port for
+    /// reconnection on error.
+    pub fn send_raw(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.write_frame(bytes)
+    }
+
+    /// Send a message, and wait for a reply of type `expected_reply_type`. Returns the reply's
+    /// payload. If no reply arrives within `timeout`, the message is re-sent, up to `retries`
+    /// times. Other frames received while waiting (eg telemetry) are discarded.
+    pub fn request<T: MessageType + TryFrom<u8>>(
+        &mut self,
+        msg_type: T,
+        payload: &[u8],
+        expected_reply_type: T,
+        timeout: Duration,
+        retries: u8,
+    ) -> Result<Vec<u8>, Error> {
+        let tx_buf = encode_frame(msg_type, payload)?;
+
+        for _ in 0..=retries {
+            self.write_frame(&tx_buf)?;
+            let sent = Instant::now();
+
+            while sent.elapsed() < timeout {
+                match self.receive::<T>() {
+                    Ok(Some(frame)) => {
+                        if frame.msg_type.val() == expected_reply_type.val() {
+                            self.stats.latency.record(sent.elapsed());
+                            return Ok(frame.payload);
+                        }
+                    }
+                    Ok(None) => {
+                        // Reading the port directly blocks for its timeout; the worker's
+                        // channel doesn't.
+                        if self.worker.is_some() {
+                            thread::sleep(Duration::from_millis(1));
+                        }
+                    }
+                    // A corrupted frame may have been our reply; keep waiting, and let the retry
+                    // handle it.
+                    Err(Error::Crc(_) | Error::ProtocolMismatch(_) | Error::Timeout) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+
+            self.stats.timeouts += 1;
+        }
+
+        Err(Error::Timeout)
+    }
+
+    /// Write an already-framed message, either directly or via the worker.
+    pub(crate) fn write_frame(&mut self, buf: &[u8]) -> Result<(), Error> {
+        let result = match &self.worker {
+            Some(worker) => worker.send(buf.to_vec()),
+            None => self.get_port()?.write(buf).map_err(Error::from),
+        };
+
+        self.record_write(buf, result)
+    }
+
+    /// Timestamp, log and count a frame we've written, or flag the port for reconnection if the
+    /// write failed.
+    fn record_write(&mut self, buf: &[u8], result: Result<(), Error>) -> Result<(), Error> {
+        match &result {
+            Ok(()) => {
+                self.last_query = Instant::now();
+                if let Some(log) = &mut self.frame_log {
+                    log.record(capture::Direction::Tx, buf, self.last_query);
+                }
+                // The worker logs and counts what it sends.
+                if self.worker.is_none() {
+                    self.log_pcap(capture::Direction::Tx, buf);
+                    self.stats.record_sent(buf.len(), self.last_query);
+                }
+            }
+            Err(Error::Io(e)) => self.report_io_error(e),
+            Err(_) => (),
+        }
+        result
+    }
+}
+
+fn push_hotplug_event(events: &mut VecDeque<HotplugEvent>, event: HotplugEvent) {
+    if events.len() >= MAX_HOTPLUG_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(event);
+}
+
+/// The pcapng link type for frames logged over a connection.
+fn pcap_link_type(connection_type: ConnectionType) -> u16 {
+    match connection_type {
+        ConnectionType::Usb => pcapng::LINKTYPE_USER0,
+        ConnectionType::Can => pcapng::LINKTYPE_CAN_SOCKETCAN,
     }
 }
 
 /// Send a payload-less command, ie the only useful data being message-type.
-/// Does not handle responses.
-pub fn send_cmd<T: MessageType>(msg_type: T, port: &mut Port) -> Result<(), io::Error> {
+/// Does not handle responses; use `FrameDecoder` for that.
+pub fn send_cmd<T: MessageType>(
+    msg_type: T,
+    port: &mut (impl Transport + ?Sized),
+) -> Result<(), Error> {
     send_payload::<T, 4>(msg_type, &[], port)
 }
 
@@ -193,16 +1079,120 @@ pub fn send_cmd<T: MessageType>(msg_type: T, port: &mut Port) -> Result<(), io::
 /// payload, then CRC.
 /// `N` is the entire message size, including the USB header. (Can't have it be payload size
 /// due to restrictions)
+/// See `send_msg` for a version that doesn't need `N`.
 pub fn send_payload<T: MessageType, const N: usize>(
     msg_type: T,
     payload: &[u8],
-    port: &mut Port,
-) -> Result<(), io::Error> {
+    port: &mut (impl Transport + ?Sized),
+) -> Result<(), Error> {
+    port.write(&encode_payload::<T, N>(msg_type, payload)?)?;
+
+    Ok(())
+}
+
+/// Send a payload, using our format; see `send_payload`. The message size is determined from
+/// the message type (and the MAVLink length byte for telemetry), and the payload length is
+/// checked against it, so there's no need to specify `N`.
+pub fn send_msg<T: MessageType>(
+    msg_type: T,
+    payload: &[u8],
+    port: &mut (impl Transport + ?Sized),
+) -> Result<(), Error> {
+    port.write(&encode_frame(msg_type, payload)?)?;
+
+    Ok(())
+}
+
+/// The payload size for a message; for telemetry, this comes from the MAVLink length byte.
+fn payload_size<T: MessageType>(msg_type: &T, payload: &[u8]) -> Result<usize, Error> {
+    if msg_type.val() != MsgType::Telemetry.val() {
+        return Ok(msg_type.payload_size());
+    }
+
+    match payload.get(1) {
+        Some(len) => Ok(*len as usize + MAVLINK_SIZE),
+        None => Err(Error::ProtocolMismatch(
+            "Telemetry payload is missing its length byte".to_owned(),
+        )),
+    }
+}
+
+/// Build a message in our format, sized at runtime from the message type; see `send_msg`.
+pub fn encode_frame<T: MessageType>(msg_type: T, payload: &[u8]) -> Result<Vec<u8>, Error> {
+    encode_frame_from(DEVICE_CODE_PC, msg_type, payload)
+}
+
+/// Build a message as if sent by the device with this code; eg to simulate a device.
+pub(crate) fn encode_frame_from<T: MessageType>(
+    device_code: u8,
+    msg_type: T,
+    payload: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let payload_size = payload_size(&msg_type, payload)?;
+
+    // The CRC function takes the length as a `u8`.
+    let max = u8::MAX as usize - PAYLOAD_START_I;
+    if payload_size > max {
+        return Err(Error::PayloadTooLarge {
+            size: payload_size,
+            max,
+        });
+    }
+
+    // Telemetry may be passed in a buffer sized for the largest MAVLink message.
+    let is_telemetry = msg_type.val() == MsgType::Telemetry.val();
+
+    if payload.len() > payload_size && !is_telemetry {
+        return Err(Error::PayloadTooLarge {
+            size: payload.len(),
+            max: payload_size,
+        });
+    }
+
+    if payload.len() < payload_size {
+        return Err(Error::ProtocolMismatch(format!(
+            "Message type {} needs a {payload_size}-byte payload; got {} bytes",
+            msg_type.val(),
+            payload.len()
+        )));
+    }
+
+    // start byte, device type byte, message type byte, payload, CRC.
+    let crc_i = PAYLOAD_START_I + payload_size;
+    let mut tx_buf = vec![0; crc_i + 1];
+
+    tx_buf[0] = MSG_START;
+    tx_buf[1] = device_code;
+    tx_buf[2] = msg_type.val();
+
+    tx_buf[PAYLOAD_START_I..crc_i].copy_from_slice(&payload[..payload_size]);
+
+    tx_buf[crc_i] = anyleaf_usb::calc_crc(&anyleaf_usb::CRC_LUT, &tx_buf[..crc_i], crc_i as u8);
+
+    Ok(tx_buf)
+}
+
+/// Build a message in our format; see `send_payload`.
+fn encode_payload<T: MessageType, const N: usize>(
+    msg_type: T,
+    payload: &[u8],
+) -> Result<[u8; N], Error> {
     // N is the total packet size.
-    let mut payload_size = msg_type.payload_size();
+    let payload_size = payload_size(&msg_type, payload)?;
 
-    if msg_type.val() == MsgType::Telemetry.val() {
-        payload_size = payload[1] as usize + MAVLINK_SIZE;
+    if payload_size + PAYLOAD_START_I + 1 > N {
+        return Err(Error::PayloadTooLarge {
+            size: payload_size,
+            max: N.saturating_sub(PAYLOAD_START_I + 1),
+        });
+    }
+
+    if payload.len() < payload_size {
+        return Err(Error::ProtocolMismatch(format!(
+            "Message type {} needs a {payload_size}-byte payload; got {} bytes",
+            msg_type.val(),
+            payload.len()
+        )));
     }
 
     // start byte, device type byte, message type byte, payload, CRC.
@@ -221,9 +1211,36 @@ pub fn send_payload<T: MessageType, const N: usize>(
         (payload_size + PAYLOAD_START_I) as u8,
     );
 
-    port.write_all(&tx_buf)?;
+    Ok(tx_buf)
+}
 
-    Ok(())
+/// Where to load the window icon from. PNG and ICO are supported.
+#[derive(Clone, Copy, Debug)]
+pub enum IconSource<'a> {
+    /// A file path, read at runtime.
+    Path(&'a Path),
+    /// Embedded data, eg from `include_bytes!`.
+    Bytes(&'a [u8]),
+}
+
+impl IconSource<'_> {
+    /// Decode the image into the format egui expects.
+    pub fn load(&self) -> Result<egui::IconData, Error> {
+        let image = match self {
+            Self::Path(path) => image::open(path),
+            Self::Bytes(bytes) => image::load_from_memory(bytes),
+        }
+        .map_err(|e| Error::Icon(e.to_string()))?
+        .into_rgba8();
+
+        let (width, height) = image.dimensions();
+
+        Ok(egui::IconData {
+            rgba: image.into_raw(),
+            width,
+            height,
+        })
+    }
 }
 
 pub fn run<T: eframe::App + 'static>(
@@ -231,16 +1248,13 @@ pub fn run<T: eframe::App + 'static>(
     window_title: &str,
     window_width: f32,
     window_height: f32,
-    icon: Option<&str>,
-) -> Result<(), eframe::Error> {
-    let mut viewport = egui::ViewportBuilder::default().with_inner_size([window_width, window_height]);
-
-    if let Some(path) = icon {
-        // todo: Problem where it requires a string literal.
-        // let icon_bytes: &[u8] = include_bytes!(path);
-        // let icon_data = eframe::icon_data::from_png_bytes(icon_bytes);
+    icon: Option<IconSource>,
+) -> Result<(), Error> {
+    let mut viewport =
+        egui::ViewportBuilder::default().with_inner_size([window_width, window_height]);
 
-        // viewport = viewport.with_icon(icon_data.unwrap());
+    if let Some(icon) = icon {
+        viewport = viewport.with_icon(icon.load()?);
     }
 
     let options = eframe::NativeOptions {
@@ -248,9 +1262,224 @@ pub fn run<T: eframe::App + 'static>(
         ..Default::default()
     };
 
-    eframe::run_native(
-        window_title,
-        options,
-        Box::new(|_cc| Ok(Box::new(state))),
-    )
+    eframe::run_native(window_title, options, Box::new(|_cc| Ok(Box::new(state))))
+        .map_err(|e| Error::Gui(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::tests::TestMsg;
+
+    const MISSING_SN: &str = "no-such-device";
+
+    fn missing_device() -> StateCommon {
+        let mut state = StateCommon::new(MISSING_SN, SerialConfig::default());
+        state.match_rules = vec![MatchRule::serial_number(MISSING_SN)];
+        state
+    }
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// App state attached to a device that never replies. Returns the device's end of the link.
+    fn silent_device() -> (StateCommon, Loopback) {
+        let (app, device) = Loopback::pair();
+
+        let config = SerialConfig {
+            disconnect_timeout: TIMEOUT,
+            ..Default::default()
+        };
+        let mut state = StateCommon::new("", config);
+        state.attach(Box::new(app), ConnectionType::Usb);
+
+        (state, device)
+    }
+
+    /// Call `poll`, then `tick`, every few ms for `duration`. Returns the status after each tick.
+    fn run(
+        state: &mut StateCommon,
+        duration: Duration,
+        mut poll: impl FnMut(&mut StateCommon),
+    ) -> Vec<ConnectionStatus> {
+        let start = Instant::now();
+        let mut result = Vec::new();
+
+        while start.elapsed() < duration {
+            poll(state);
+            state.tick(Instant::now());
+            result.push(state.connection_status.clone());
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        result
+    }
+
+    fn assert_stays_stale(statuses: &[ConnectionStatus]) {
+        let stale = statuses
+            .iter()
+            .position(|s| *s == ConnectionStatus::Stale)
+            .expect("The link never went stale");
+
+        assert!(statuses[stale..]
+            .iter()
+            .all(|s| *s == ConnectionStatus::Stale));
+    }
+
+    #[test]
+    fn polling_doesnt_clear_stale() {
+        let (mut state, mut device) = silent_device();
+
+        let statuses = run(&mut state, TIMEOUT * 4, |state| {
+            state.send_cmd(TestMsg::Ping).unwrap();
+        });
+        assert_stays_stale(&statuses);
+
+        // Only a reply does.
+        device
+            .write(&encode_frame(TestMsg::Ping, &[]).unwrap())
+            .unwrap();
+        assert!(state.receive::<TestMsg>().unwrap().is_some());
+        state.tick(Instant::now());
+        assert_eq!(state.connection_status, ConnectionStatus::Connected);
+    }
+
+    #[test]
+    fn worker_forwards_raw_bytes() {
+        let (mut state, mut device) = silent_device();
+        state.frame_log = Some(FrameLog::default());
+        state.start_worker::<TestMsg>(None);
+
+        // Bytes that aren't part of a frame, then one that is.
+        let mut sent = vec![0xde, 0xad];
+        sent.extend(encode_frame(TestMsg::Ping, &[]).unwrap());
+        device.write(&sent).unwrap();
+
+        let start = Instant::now();
+        while !matches!(state.receive::<TestMsg>(), Ok(Some(_))) {
+            assert!(start.elapsed() < TIMEOUT, "The frame never arrived");
+        }
+
+        let log = state.frame_log.as_ref().unwrap();
+        let raw: Vec<u8> = log
+            .raw_received
+            .iter()
+            .flat_map(|f| f.bytes.clone())
+            .collect();
+        assert_eq!(raw, sent);
+        assert_eq!(log.received.len(), 1);
+    }
+
+    #[test]
+    fn heartbeat_doesnt_clear_stale() {
+        let (mut state, mut device) = silent_device();
+        state.heartbeat =
+            Some(Heartbeat::new(TestMsg::Ping, &[], Duration::from_millis(20)).unwrap());
+
+        let statuses = run(&mut state, TIMEOUT * 4, |_| ());
+        assert_stays_stale(&statuses);
+        assert_eq!(state.reconnect_count, 0);
+
+        let mut buf = [0; 64];
+        assert!(device.read(&mut buf).unwrap() > 0);
+    }
+
+    #[test]
+    fn encode_checks_payload_size() {
+        let frame = encode_frame(TestMsg::Params, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(frame.len(), PAYLOAD_START_I + 4 + 1);
+
+        assert!(matches!(
+            encode_frame(TestMsg::Params, &[1, 2, 3]),
+            Err(Error::ProtocolMismatch(_))
+        ));
+        assert!(matches!(
+            encode_frame(TestMsg::Params, &[1, 2, 3, 4, 5]),
+            Err(Error::PayloadTooLarge { size: 5, max: 4 })
+        ));
+    }
+
+    #[test]
+    fn encode_payload_checks_sizes() {
+        let frame = encode_payload::<_, 10>(TestMsg::Params, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(
+            frame[..8],
+            encode_frame(TestMsg::Params, &[1, 2, 3, 4]).unwrap()
+        );
+
+        assert!(matches!(
+            encode_payload::<_, 10>(TestMsg::Params, &[1, 2, 3]),
+            Err(Error::ProtocolMismatch(_))
+        ));
+        // `N` is smaller than the frame.
+        assert!(matches!(
+            encode_payload::<_, 7>(TestMsg::Params, &[1, 2, 3, 4]),
+            Err(Error::PayloadTooLarge { size: 4, max: 3 })
+        ));
+    }
+
+    #[test]
+    fn encode_telemetry() {
+        assert!(matches!(
+            encode_frame(TestMsg::Telemetry, &[0]),
+            Err(Error::ProtocolMismatch(_))
+        ));
+        assert!(matches!(
+            encode_payload::<_, 64>(TestMsg::Telemetry, &[]),
+            Err(Error::ProtocolMismatch(_))
+        ));
+
+        // Telemetry may be passed in a buffer sized for the largest message; only the part
+        // given by the length byte is sent.
+        let len = 5;
+        let mut buf = vec![0; len + MAVLINK_SIZE + 16];
+        buf[1] = len as u8;
+        let frame = encode_frame(TestMsg::Telemetry, &buf).unwrap();
+        assert_eq!(frame.len(), PAYLOAD_START_I + len + MAVLINK_SIZE + 1);
+    }
+
+    #[test]
+    fn receive_throttles_reconnects() {
+        let mut state = missing_device();
+
+        assert!(state.receive::<TestMsg>().is_err());
+        let attempt = state.last_connect_attempt;
+        assert!(attempt.is_some());
+
+        for _ in 0..50 {
+            assert!(matches!(state.receive::<TestMsg>(), Err(Error::NoDevice)));
+        }
+        assert_eq!(state.last_connect_attempt, attempt);
+    }
+
+    #[test]
+    fn failed_searches_arent_reconnects() {
+        let mut state = missing_device();
+        let start = Instant::now();
+
+        for i in 0..50 {
+            state.receive::<TestMsg>().ok();
+            // Past the retry interval, so each tick searches again.
+            state.tick(start + Duration::from_millis(i * RETRY_INTERVAL_MS));
+        }
+
+        assert_eq!(state.reconnect_count, 0);
+        assert_eq!(state.stats.reconnects, 0);
+    }
+
+    #[test]
+    fn io_errors_are_reconnects() {
+        let (app, _device) = Loopback::pair();
+        let mut state = StateCommon::new("", SerialConfig::default());
+        state.attach(Box::new(app), ConnectionType::Usb);
+
+        state.report_io_error(&io::ErrorKind::BrokenPipe.into());
+        state.get_port().unwrap();
+        state.get_port().unwrap();
+
+        assert_eq!(state.reconnect_count, 1);
+        assert_eq!(
+            state.last_reconnect.map(|(reason, _)| reason),
+            Some(ReconnectReason::IoError(io::ErrorKind::BrokenPipe))
+        );
+    }
 }
@@ -0,0 +1,130 @@
+//! Handles several devices at once; eg a flight controller over USB, and an ESC over CAN. Each
+//! device has its own `StateCommon`, so its own port, status, and `last_response`. Devices are
+//! addressed by a key: their serial number, or port name.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use anyleaf_usb::MessageType;
+
+use crate::{discovery::MatchRule, Error, Frame, SerialConfig, StateCommon};
+
+#[derive(Default)]
+pub struct DeviceManager {
+    /// Sorted by key, so GUI listings are stable.
+    devices: BTreeMap<String, StateCommon>,
+}
+
+impl DeviceManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a device with custom state; eg with its own `match_rules`, or an attached transport.
+    /// Returns the device previously at this key, if any.
+    pub fn add(&mut self, key: &str, state: StateCommon) -> Option<StateCommon> {
+        self.devices.insert(key.to_owned(), state)
+    }
+
+    /// Add a USB device, found by serial number. Unlike a standalone `StateCommon`, this doesn't
+    /// fall back to SLCAN adapters, so it can't claim another device's port.
+    pub fn add_serial(&mut self, serial_number: &str, config: SerialConfig) -> &mut StateCommon {
+        let mut state = StateCommon::new(serial_number, config);
+        state.match_rules = vec![MatchRule::serial_number(serial_number)];
+
+        self.insert(serial_number, state)
+    }
+
+    /// Add a device at a specific port, eg a CAN adapter without a serial number. It only matches
+    /// that port, even if `port_override` is cleared, so it can't claim another device's port.
+    pub fn add_port(&mut self, port_name: &str, config: SerialConfig) -> &mut StateCommon {
+        let mut state = StateCommon::new("", config);
+        state.port_override = Some(port_name.to_owned());
+        state.match_rules = vec![MatchRule::port_name(port_name)];
+
+        self.insert(port_name, state)
+    }
+
+    fn insert(&mut self, key: &str, state: StateCommon) -> &mut StateCommon {
+        self.devices.insert(key.to_owned(), state);
+        self.devices.get_mut(key).unwrap()
+    }
+
+    /// Remove a device, closing its port.
+    pub fn remove(&mut self, key: &str) -> Option<StateCommon> {
+        self.devices.remove(key)
+    }
+
+    pub fn get(&self, key: &str) -> Result<&StateCommon, Error> {
+        self.devices
+            .get(key)
+            .ok_or_else(|| Error::UnknownDevice(key.to_owned()))
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Result<&mut StateCommon, Error> {
+        self.devices
+            .get_mut(key)
+            .ok_or_else(|| Error::UnknownDevice(key.to_owned()))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.devices.keys().map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &StateCommon)> {
+        self.devices.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&str, &mut StateCommon)> {
+        self.devices.iter_mut().map(|(k, v)| (k.as_str(), v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Drive each device's connection state; see `StateCommon::tick`.
+    pub fn tick(&mut self, now: Instant) {
+        for state in self.devices.values_mut() {
+            state.tick(now);
+        }
+    }
+
+    /// Send a message to one device; see `StateCommon::send_msg`.
+    pub fn send_msg<T: MessageType>(
+        &mut self,
+        key: &str,
+        msg_type: T,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        self.get_mut(key)?.send_msg(msg_type, payload)
+    }
+
+    /// Receive the next frame from one device; see `StateCommon::receive`.
+    pub fn receive<T: MessageType + TryFrom<u8>>(
+        &mut self,
+        key: &str,
+    ) -> Result<Option<Frame<T>>, Error> {
+        self.get_mut(key)?.receive()
+    }
+
+    /// Send a request to one device, and wait for its reply; see `StateCommon::request`.
+    pub fn request<T: MessageType + TryFrom<u8>>(
+        &mut self,
+        key: &str,
+        msg_type: T,
+        payload: &[u8],
+        expected_reply_type: T,
+        timeout: Duration,
+        retries: u8,
+    ) -> Result<Vec<u8>, Error> {
+        self.get_mut(key)?
+            .request(msg_type, payload, expected_reply_type, timeout, retries)
+    }
+}
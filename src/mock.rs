@@ -0,0 +1,305 @@
+//! A simulated device, for exercising the serial path without hardware. It speaks our framing
+//! over any `Transport`; usually the far end of a `Loopback` pair, with the near end passed to
+//! `StateCommon::attach`. Responses are scripted per message type, and it can inject latency,
+//! dropped bytes, and corrupt CRCs.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyleaf_usb::MessageType;
+
+use crate::{encode_frame_from, Error, Frame, FrameDecoder, Transport};
+
+/// A custom response handler; see `MockDevice::set_handler`.
+pub type MockHandler<T> = Box<dyn FnMut(&Frame<T>) -> Vec<(T, Vec<u8>)> + Send>;
+
+pub struct MockDevice<T: MessageType> {
+    pub transport: Box<dyn Transport>,
+    /// Sent in the device code byte of our responses.
+    pub device_code: u8,
+    /// Canned responses, keyed by the request's message type byte.
+    responses: HashMap<u8, Vec<Vec<u8>>>,
+    handler: Option<MockHandler<T>>,
+    /// Delay between receiving a request, and sending its response.
+    pub latency: Duration,
+    /// The probability (0 to 1) of dropping each byte we send.
+    pub drop_rate: f32,
+    /// The probability (0 to 1) of corrupting the CRC of each frame we send.
+    pub corrupt_crc_rate: f32,
+    /// Every frame we've received, for making assertions in tests.
+    pub received: Vec<Frame<u8>>,
+    decoder: FrameDecoder,
+    /// Responses waiting for `latency` to elapse.
+    scheduled: VecDeque<(Instant, Vec<u8>)>,
+    /// State for a small PRNG; keeps fault injection reproducible without another dependency.
+    rng: u32,
+}
+
+impl<T: MessageType + TryFrom<u8>> MockDevice<T> {
+    pub fn new(transport: Box<dyn Transport>, device_code: u8) -> Self {
+        Self {
+            transport,
+            device_code,
+            responses: HashMap::new(),
+            handler: None,
+            latency: Duration::ZERO,
+            drop_rate: 0.,
+            corrupt_crc_rate: 0.,
+            received: Vec::new(),
+            decoder: FrameDecoder::new(),
+            scheduled: VecDeque::new(),
+            rng: 0x1234_5678,
+        }
+    }
+
+    /// Reply to each message of type `request` with a message of type `reply`. Can be called
+    /// more than once for the same request type, to send several replies.
+    pub fn respond(&mut self, request: T, reply: T, payload: &[u8]) -> Result<(), Error> {
+        let frame = encode_frame_from(self.device_code, reply, payload)?;
+
+        self.responses.entry(request.val()).or_default().push(frame);
+
+        Ok(())
+    }
+
+    /// Set a handler that's called for every received frame, returning replies to send. These
+    /// are sent after any canned responses.
+    pub fn set_handler(&mut self, handler: MockHandler<T>) {
+        self.handler = Some(handler);
+    }
+
+    /// Seed the PRNG used for dropped bytes and corrupt CRCs.
+    pub fn seed(&mut self, seed: u32) {
+        // Xorshift gets stuck at 0.
+        self.rng = seed.max(1);
+    }
+
+    /// Process received data, and send any responses that are due. Call this in a loop, or use
+    /// `spawn`.
+    pub fn poll(&mut self) -> Result<(), Error> {
+        self.decoder.read_from(&mut self.transport)?;
+
+        let now = Instant::now();
+
+        while let Some(result) = self.decoder.next_frame::<T>() {
+            // Bad frames from the app are ignored, as real firmware would.
+            let Ok(frame) = result else {
+                continue;
+            };
+
+            let mut replies = self
+                .responses
+                .get(&frame.msg_type.val())
+                .cloned()
+                .unwrap_or_default();
+
+            if let Some(handler) = &mut self.handler {
+                for (msg_type, payload) in handler(&frame) {
+                    replies.push(encode_frame_from(self.device_code, msg_type, &payload)?);
+                }
+            }
+
+            for reply in replies {
+                self.scheduled.push_back((now + self.latency, reply));
+            }
+
+            self.received.push(frame.into_raw());
+        }
+
+        while self.scheduled.front().is_some_and(|(due, _)| *due <= now) {
+            let (_, frame) = self.scheduled.pop_front().unwrap();
+            self.send(frame)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a frame that isn't a response to anything; eg unsolicited telemetry.
+    pub fn send_unsolicited(&mut self, msg_type: T, payload: &[u8]) -> Result<(), Error> {
+        let frame = encode_frame_from(self.device_code, msg_type, payload)?;
+        self.send(frame)
+    }
+
+    /// Write a frame, applying fault injection.
+    fn send(&mut self, mut frame: Vec<u8>) -> Result<(), Error> {
+        if self.chance(self.corrupt_crc_rate) {
+            let crc = frame.last_mut().unwrap();
+            *crc = !*crc;
+        }
+
+        if self.drop_rate > 0. {
+            let mut kept = Vec::with_capacity(frame.len());
+            for byte in frame {
+                if !self.chance(self.drop_rate) {
+                    kept.push(byte);
+                }
+            }
+            frame = kept;
+        }
+
+        Ok(self.transport.write(&frame)?)
+    }
+
+    /// Returns true with probability `p`.
+    fn chance(&mut self, p: f32) -> bool {
+        if p <= 0. {
+            return false;
+        }
+
+        // Xorshift32
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+
+        (self.rng as f32 / u32::MAX as f32) < p
+    }
+}
+
+impl<T: MessageType + TryFrom<u8> + Send + 'static> MockDevice<T> {
+    /// Run the device on a background thread, until the returned handle is dropped.
+    pub fn spawn(mut self) -> MockHandle {
+        let running = Arc::new(AtomicBool::new(true));
+        let running_ = running.clone();
+
+        let handle = thread::spawn(move || {
+            while running_.load(Ordering::Relaxed) {
+                // Read timeouts are normal; other errors mean the link's gone.
+                match self.poll() {
+                    Ok(()) | Err(Error::Timeout) => (),
+                    Err(_) => break,
+                }
+            }
+        });
+
+        MockHandle {
+            running,
+            handle: Some(handle),
+        }
+    }
+}
+
+/// Stops the device thread when dropped.
+pub struct MockHandle {
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for MockHandle {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyleaf_usb::DEVICE_CODE_PC;
+
+    use super::*;
+    use crate::{
+        frame::tests::TestMsg, ConnectionStatus, ConnectionType, Loopback, SerialConfig,
+        StateCommon,
+    };
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+    const REPLY: [u8; 4] = [1, 2, 3, 4];
+
+    /// A device that replies to pings with params, and app state attached to it.
+    fn setup(configure: impl FnOnce(&mut MockDevice<TestMsg>)) -> (StateCommon, MockHandle) {
+        let (app, device) = Loopback::pair();
+
+        let mut device = MockDevice::new(Box::new(device), DEVICE_CODE_PC);
+        device
+            .respond(TestMsg::Ping, TestMsg::Params, &REPLY)
+            .unwrap();
+        configure(&mut device);
+
+        let mut state = StateCommon::new("", SerialConfig::default());
+        state.attach(Box::new(app), ConnectionType::Usb);
+
+        (state, device.spawn())
+    }
+
+    #[test]
+    fn request() {
+        let (mut state, _device) = setup(|_| ());
+
+        let reply = state
+            .request(TestMsg::Ping, &[], TestMsg::Params, TIMEOUT, 0)
+            .unwrap();
+
+        assert_eq!(reply, REPLY);
+        assert_eq!(state.stats.frames_sent, 1);
+        assert_eq!(state.stats.timeouts, 0);
+        assert_eq!(state.stats.latency.samples, 1);
+    }
+
+    #[test]
+    fn request_times_out() {
+        let (mut state, _device) = setup(|device| device.corrupt_crc_rate = 1.);
+
+        let result = state.request(TestMsg::Ping, &[], TestMsg::Params, TIMEOUT, 2);
+
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert_eq!(state.stats.frames_sent, 3);
+        assert_eq!(state.stats.timeouts, 3);
+        assert_eq!(state.stats.decode.bad_crc, 3);
+    }
+
+    #[test]
+    fn request_retries_after_corrupt_crc() {
+        // With this seed, only the first reply is corrupted.
+        let (mut state, _device) = setup(|device| {
+            device.corrupt_crc_rate = 0.5;
+            device.seed(32);
+        });
+
+        let reply = state
+            .request(TestMsg::Ping, &[], TestMsg::Params, TIMEOUT, 3)
+            .unwrap();
+
+        assert_eq!(reply, REPLY);
+        assert_eq!(state.stats.frames_sent, 2);
+        assert_eq!(state.stats.timeouts, 1);
+        assert_eq!(state.stats.decode.bad_crc, 1);
+    }
+
+    #[test]
+    fn request_retries_after_dropped_bytes() {
+        // With this seed, bytes are dropped from the first reply only.
+        let (mut state, _device) = setup(|device| {
+            device.drop_rate = 0.5;
+            device.seed(371);
+        });
+
+        let reply = state
+            .request(TestMsg::Ping, &[], TestMsg::Params, TIMEOUT, 3)
+            .unwrap();
+
+        assert_eq!(reply, REPLY);
+        assert_eq!(state.stats.frames_sent, 2);
+        assert_eq!(state.stats.timeouts, 1);
+    }
+
+    #[test]
+    fn request_via_worker() {
+        let (mut state, _device) = setup(|_| ());
+        state.start_worker::<TestMsg>(None);
+        assert_eq!(state.connection_status, ConnectionStatus::Connected);
+
+        let reply = state
+            .request(TestMsg::Ping, &[], TestMsg::Params, TIMEOUT, 0)
+            .unwrap();
+
+        assert_eq!(reply, REPLY);
+    }
+}
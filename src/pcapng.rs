@@ -0,0 +1,202 @@
+//! Export traffic to pcapng, for inspection in Wireshark alongside other tools. Our USB frames
+//! use a user-defined link type (`LINKTYPE_USER0`; configure a dissector for it under
+//! Edit → Preferences → Protocols → DLT_USER). CAN frames use the SocketCAN link type, which
+//! Wireshark decodes natively.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyleaf_usb::MessageType;
+
+use crate::{
+    capture::{self, Direction},
+    CanFrame, Error, FrameDecoder,
+};
+
+/// Link type for our USB-serial frames; one packet per frame, including header and CRC.
+pub const LINKTYPE_USER0: u16 = 147;
+/// Link type for CAN frames, in the Linux SocketCAN format.
+pub const LINKTYPE_CAN_SOCKETCAN: u16 = 227;
+
+const BLOCK_SHB: u32 = 0x0A0D_0D0A;
+const BLOCK_IDB: u32 = 1;
+const BLOCK_EPB: u32 = 6;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_END: u16 = 0;
+const OPT_EPB_FLAGS: u16 = 2;
+
+/// SocketCAN ID flags.
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+const CAN_RTR_FLAG: u32 = 0x4000_0000;
+
+/// A pcapng writer with a single interface. Timestamps are in microseconds, which is the
+/// format's default resolution.
+pub struct PcapngWriter {
+    writer: Box<dyn Write + Send>,
+    pub link_type: u16,
+}
+
+/// A writer shared with a transport; see `SlcanTransport::pcap`. Logging through it is best
+/// effort: write errors are ignored, so a full disk doesn't take down the connection.
+pub type SharedPcapng = Arc<Mutex<PcapngWriter>>;
+
+impl PcapngWriter {
+    /// Write the section and interface headers.
+    pub fn new(mut writer: Box<dyn Write + Send>, link_type: u16) -> io::Result<Self> {
+        // Section header block; no options. The section length is unspecified.
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1_u16.to_le_bytes());
+        shb.extend_from_slice(&0_u16.to_le_bytes());
+        shb.extend_from_slice(&(-1_i64).to_le_bytes());
+        write_block(&mut writer, BLOCK_SHB, &shb)?;
+
+        // Interface description block; no snap length limit.
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&link_type.to_le_bytes());
+        idb.extend_from_slice(&0_u16.to_le_bytes());
+        idb.extend_from_slice(&0_u32.to_le_bytes());
+        write_block(&mut writer, BLOCK_IDB, &idb)?;
+
+        Ok(Self { writer, link_type })
+    }
+
+    pub fn create(path: &Path, link_type: u16) -> io::Result<Self> {
+        Self::new(Box::new(BufWriter::new(File::create(path)?)), link_type)
+    }
+
+    /// Write a packet as an enhanced packet block, tagged with its direction.
+    pub fn write_packet(
+        &mut self,
+        time: SystemTime,
+        direction: Direction,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let ts = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut epb = Vec::with_capacity(data.len() + 32);
+        // Interface ID
+        epb.extend_from_slice(&0_u32.to_le_bytes());
+        epb.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(ts as u32).to_le_bytes());
+        // Captured, and original lengths.
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(data);
+        pad(&mut epb);
+
+        // Direction flags: 1 is inbound, 2 is outbound.
+        let flags: u32 = match direction {
+            Direction::Rx => 1,
+            Direction::Tx => 2,
+        };
+        epb.extend_from_slice(&OPT_EPB_FLAGS.to_le_bytes());
+        epb.extend_from_slice(&4_u16.to_le_bytes());
+        epb.extend_from_slice(&flags.to_le_bytes());
+        epb.extend_from_slice(&OPT_END.to_le_bytes());
+        epb.extend_from_slice(&0_u16.to_le_bytes());
+
+        write_block(&mut self.writer, BLOCK_EPB, &epb)
+    }
+
+    /// Write a CAN frame, in SocketCAN format.
+    pub fn write_can_frame(
+        &mut self,
+        time: SystemTime,
+        direction: Direction,
+        frame: &CanFrame,
+    ) -> io::Result<()> {
+        let mut id = frame.id;
+        if frame.extended {
+            id |= CAN_EFF_FLAG;
+        }
+        if frame.rtr {
+            id |= CAN_RTR_FLAG;
+        }
+
+        let mut data = Vec::with_capacity(16);
+        // SocketCAN uses network byte order for the ID.
+        data.extend_from_slice(&id.to_be_bytes());
+        data.push(frame.len);
+        // Padding, and 2 reserved bytes.
+        data.extend_from_slice(&[0; 3]);
+        data.extend_from_slice(&frame.data);
+
+        self.write_packet(time, direction, &data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for PcapngWriter {
+    fn drop(&mut self) {
+        self.flush().ok();
+    }
+}
+
+/// Write a block, with its type and length header and trailer.
+fn write_block(writer: &mut impl Write, block_type: u32, body: &[u8]) -> io::Result<()> {
+    // Body lengths are always padded to 4 bytes by the callers.
+    let len = (body.len() + 12) as u32;
+
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&len.to_le_bytes())
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    while !buf.len().is_multiple_of(4) {
+        buf.push(0);
+    }
+}
+
+/// Convert a capture file (see the `capture` module) to pcapng, with one packet per decoded
+/// frame. `T` is used to determine frame sizes. Bytes that don't form a valid frame are
+/// skipped.
+pub fn export_capture<T: MessageType + TryFrom<u8>>(
+    capture_path: &Path,
+    pcapng_path: &Path,
+) -> Result<(), Error> {
+    let entries = capture::load_capture(capture_path)?;
+    // Without a recorded start time, timestamps are relative to the epoch; ordering and
+    // intervals are still correct.
+    let start = capture::capture_start(capture_path).unwrap_or(UNIX_EPOCH);
+
+    let mut writer = PcapngWriter::create(pcapng_path, LINKTYPE_USER0)?;
+
+    // Each direction is a separate byte stream.
+    let mut tx_decoder = FrameDecoder::new();
+    let mut rx_decoder = FrameDecoder::new();
+
+    for entry in entries {
+        let decoder = match entry.direction {
+            Direction::Tx => &mut tx_decoder,
+            Direction::Rx => &mut rx_decoder,
+        };
+        decoder.push(&entry.bytes);
+
+        let time = start + entry.time;
+
+        while let Some(result) = decoder.next_frame::<T>() {
+            if let Ok(frame) = result {
+                writer.write_packet(time, entry.direction, &frame.into_raw().to_bytes())?;
+            }
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! A virtual device on a pseudo-terminal (PTY) pair, for running real apps against a simulated
+//! device without USB. The app opens the slave side by path, like any serial port; set
+//! `StateCommon::port_override` to `VirtualDevice::path`. The simulated device runs on the
+//! master side.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use serialport::{SerialPort, TTYPort};
+
+use crate::{Error, Port, Transport};
+
+/// Read timeout for the master side; matches the app side.
+const MASTER_TIMEOUT_MS: u64 = 10;
+
+pub struct VirtualDevice {
+    path: String,
+    /// We keep the slave side open; otherwise, reads on the master fail whenever the app
+    /// doesn't have the port open.
+    _slave: TTYPort,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl VirtualDevice {
+    /// Create the PTY pair. Returns the master side, for the caller to drive; eg by passing it to
+    /// `MockDevice::new`.
+    pub fn open() -> Result<(Self, Port), Error> {
+        let (mut master, slave) = TTYPort::pair().map_err(|e| Error::Io(e.into()))?;
+        master
+            .set_timeout(Duration::from_millis(MASTER_TIMEOUT_MS))
+            .map_err(|e| Error::Io(e.into()))?;
+
+        let path = slave.name().ok_or_else(|| {
+            Error::Io(std::io::Error::other("Unable to get the PTY's slave path"))
+        })?;
+
+        let result = Self {
+            path,
+            _slave: slave,
+            running: Arc::new(AtomicBool::new(true)),
+            handle: None,
+        };
+
+        Ok((result, Box::new(master)))
+    }
+
+    /// Create the PTY pair, and run `handler` repeatedly on the master side, in a background
+    /// thread, until this is dropped or the handler returns an error. Read timeouts from the
+    /// handler are ignored.
+    pub fn spawn<F>(mut handler: F) -> Result<Self, Error>
+    where
+        F: FnMut(&mut dyn Transport) -> Result<(), Error> + Send + 'static,
+    {
+        let (mut result, mut master) = Self::open()?;
+        let running = result.running.clone();
+
+        result.handle = Some(thread::spawn(move || {
+            while running.load(Ordering::Relaxed) {
+                match handler(&mut master) {
+                    Ok(()) | Err(Error::Timeout) => (),
+                    Err(_) => break,
+                }
+            }
+        }));
+
+        Ok(result)
+    }
+
+    /// The slave side's path, eg `/dev/pts/3`, for the app to open.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for VirtualDevice {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
@@ -0,0 +1,485 @@
+//! Support for serial-line CAN (SLCAN) adapters. These speak an ASCII command set over a
+//! USB-serial port. We segment our USB-framed messages into classic CAN frames, and reassemble
+//! received frames' data into a byte stream, so the rest of the crate doesn't need to know the
+//! difference.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Read, Write},
+    time::SystemTime,
+};
+
+use crate::{capture::Direction, pcapng::SharedPcapng, Port, Transport};
+
+/// Terminates every SLCAN command and frame.
+const CR: u8 = b'\r';
+/// Sent by the adapter in place of `CR` when it rejects a command.
+const BELL: u8 = 0x07;
+
+/// Classic CAN frames carry at most 8 data bytes.
+pub const CAN_DATA_LEN: usize = 8;
+
+/// The CAN ID we use for outgoing frames by default.
+pub const DEFAULT_TX_ID: u32 = 0x100;
+
+const READ_CHUNK_SIZE: usize = 256;
+
+/// The longest valid line is an extended frame with 8 data bytes and a timestamp.
+const MAX_LINE_LEN: usize = 30;
+
+/// Standard SLCAN bitrates, set with the `S0` to `S8` commands.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Bitrate {
+    B10k,
+    B20k,
+    B50k,
+    B100k,
+    B125k,
+    B250k,
+    B500k,
+    B800k,
+    #[default]
+    B1M,
+}
+
+impl Bitrate {
+    /// The digit following `S` in the set-bitrate command.
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::B10k => b'0',
+            Self::B20k => b'1',
+            Self::B50k => b'2',
+            Self::B100k => b'3',
+            Self::B125k => b'4',
+            Self::B250k => b'5',
+            Self::B500k => b'6',
+            Self::B800k => b'7',
+            Self::B1M => b'8',
+        }
+    }
+
+    pub fn bits_per_sec(&self) -> u32 {
+        match self {
+            Self::B10k => 10_000,
+            Self::B20k => 20_000,
+            Self::B50k => 50_000,
+            Self::B100k => 100_000,
+            Self::B125k => 125_000,
+            Self::B250k => 250_000,
+            Self::B500k => 500_000,
+            Self::B800k => 800_000,
+            Self::B1M => 1_000_000,
+        }
+    }
+}
+
+/// A classic CAN frame, as sent or received by an SLCAN adapter.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct CanFrame {
+    /// 11 bits for standard frames; 29 bits for extended ones.
+    pub id: u32,
+    pub extended: bool,
+    /// Remote transmission request; carries a length, but no data.
+    pub rtr: bool,
+    pub len: u8,
+    pub data: [u8; CAN_DATA_LEN],
+    /// Milliseconds, wrapping at 60,000; only present if timestamps are enabled on the adapter.
+    pub timestamp: Option<u16>,
+}
+
+impl CanFrame {
+    /// Create a data frame. `data` must be at most 8 bytes.
+    pub fn new(id: u32, extended: bool, data: &[u8]) -> Self {
+        let mut result = Self {
+            id,
+            extended,
+            len: data.len() as u8,
+            ..Default::default()
+        };
+        result.data[..data.len()].copy_from_slice(data);
+        result
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    /// Encode as an SLCAN `t`, `T`, `r` or `R` command, including the trailing `CR`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(27);
+
+        let (cmd, id_digits) = match (self.extended, self.rtr) {
+            (false, false) => (b't', 3),
+            (true, false) => (b'T', 8),
+            (false, true) => (b'r', 3),
+            (true, true) => (b'R', 8),
+        };
+
+        result.push(cmd);
+        push_hex(&mut result, self.id, id_digits);
+        push_hex(&mut result, self.len as u32, 1);
+
+        if !self.rtr {
+            for byte in self.data() {
+                push_hex(&mut result, *byte as u32, 2);
+            }
+        }
+
+        if let Some(ts) = self.timestamp {
+            push_hex(&mut result, ts as u32, 4);
+        }
+
+        result.push(CR);
+        result
+    }
+
+    /// Decode a received SLCAN frame line, without its trailing `CR`.
+    pub fn decode(line: &[u8]) -> Result<Self, SlcanError> {
+        let (extended, rtr, id_digits) = match line.first() {
+            Some(b't') => (false, false, 3),
+            Some(b'T') => (true, false, 8),
+            Some(b'r') => (false, true, 3),
+            Some(b'R') => (true, true, 8),
+            Some(c) => return Err(SlcanError::UnknownCommand(*c)),
+            None => return Err(SlcanError::Empty),
+        };
+
+        let mut i = 1;
+        let id = parse_hex(line.get(i..i + id_digits).ok_or(SlcanError::BadLength)?)?;
+        i += id_digits;
+
+        let len = parse_hex(line.get(i..i + 1).ok_or(SlcanError::BadLength)?)? as usize;
+        i += 1;
+        if len > CAN_DATA_LEN {
+            return Err(SlcanError::BadLength);
+        }
+
+        let mut data = [0; CAN_DATA_LEN];
+        if !rtr {
+            for byte in data.iter_mut().take(len) {
+                *byte = parse_hex(line.get(i..i + 2).ok_or(SlcanError::BadLength)?)? as u8;
+                i += 2;
+            }
+        }
+
+        let timestamp = match line.len() - i {
+            0 => None,
+            4 => Some(parse_hex(&line[i..])? as u16),
+            _ => return Err(SlcanError::BadLength),
+        };
+
+        Ok(Self {
+            id,
+            extended,
+            rtr,
+            len: len as u8,
+            data,
+            timestamp,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SlcanError {
+    Empty,
+    /// The line didn't start with a frame command we know.
+    UnknownCommand(u8),
+    BadLength,
+    BadHex,
+}
+
+impl fmt::Display for SlcanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "Empty SLCAN line"),
+            Self::UnknownCommand(c) => write!(f, "Unknown SLCAN command: {:?}", *c as char),
+            Self::BadLength => write!(f, "SLCAN frame has an invalid length"),
+            Self::BadHex => write!(f, "SLCAN frame contains an invalid hex digit"),
+        }
+    }
+}
+
+impl std::error::Error for SlcanError {}
+
+fn push_hex(buf: &mut Vec<u8>, val: u32, digits: usize) {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+    for i in (0..digits).rev() {
+        buf.push(DIGITS[((val >> (i * 4)) & 0xf) as usize]);
+    }
+}
+
+fn parse_hex(digits: &[u8]) -> Result<u32, SlcanError> {
+    let mut result = 0;
+    for d in digits {
+        let val = (*d as char).to_digit(16).ok_or(SlcanError::BadHex)?;
+        result = (result << 4) | val;
+    }
+    Ok(result)
+}
+
+/// A serial-line CAN adapter. Implements `Transport` by segmenting written bytes into CAN frames
+/// on `tx_id`, and passing up data from received frames.
+pub struct SlcanTransport {
+    pub port: Port,
+    /// The CAN ID we send frames on.
+    pub tx_id: u32,
+    /// Send on 29-bit IDs instead of 11-bit ones.
+    pub extended: bool,
+    /// If set, only data from frames with this ID is passed up through `Transport::read`.
+    pub rx_id: Option<u32>,
+    /// Commands the adapter rejected.
+    pub nack_count: u32,
+    /// If set, every frame sent and received is logged here.
+    pub pcap: Option<SharedPcapng>,
+    /// Received bytes that aren't yet a complete line.
+    line: Vec<u8>,
+    frames: VecDeque<CanFrame>,
+    /// Frame data not yet consumed by `Transport::read`.
+    rx: VecDeque<u8>,
+}
+
+impl SlcanTransport {
+    /// Wrap a port without sending any commands; use this if the adapter's channel is already
+    /// open.
+    pub fn new(port: Port) -> Self {
+        Self {
+            port,
+            tx_id: DEFAULT_TX_ID,
+            extended: false,
+            rx_id: None,
+            nack_count: 0,
+            pcap: None,
+            line: Vec::new(),
+            frames: VecDeque::new(),
+            rx: VecDeque::new(),
+        }
+    }
+
+    /// Configure the adapter's bitrate and timestamps, then open the CAN channel.
+    pub fn open(port: Port, bitrate: Bitrate, timestamps: bool) -> io::Result<Self> {
+        let mut result = Self::new(port);
+
+        // The adapter ignores configuration commands while the channel is open, so close it
+        // first in case a previous session left it open.
+        result.command(b"C")?;
+        result.command(&[b'S', bitrate.code()])?;
+        result.command(if timestamps { b"Z1" } else { b"Z0" })?;
+        result.command(b"O")?;
+
+        Ok(result)
+    }
+
+    /// Close the CAN channel. The port remains open.
+    pub fn close(&mut self) -> io::Result<()> {
+        self.command(b"C")
+    }
+
+    /// Send a command, appending the `CR`. We don't wait for the adapter's acknowledgement;
+    /// rejections are counted in `nack_count` as they're received.
+    pub fn command(&mut self, cmd: &[u8]) -> io::Result<()> {
+        self.port.write_all(cmd)?;
+        self.port.write_all(&[CR])
+    }
+
+    pub fn send_frame(&mut self, frame: &CanFrame) -> io::Result<()> {
+        self.port.write_all(&frame.encode())?;
+        self.log(Direction::Tx, frame);
+        Ok(())
+    }
+
+    fn log(&self, direction: Direction, frame: &CanFrame) {
+        if let Some(pcap) = &self.pcap {
+            pcap.lock()
+                .unwrap()
+                .write_can_frame(SystemTime::now(), direction, frame)
+                .ok();
+        }
+    }
+
+    /// Get the next received frame, if one is available. Note that frames returned here aren't
+    /// passed up through `Transport::read`.
+    pub fn recv_frame(&mut self) -> io::Result<Option<CanFrame>> {
+        if self.frames.is_empty() {
+            match self.poll() {
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => (),
+                r => r?,
+            }
+        }
+        Ok(self.frames.pop_front())
+    }
+
+    /// Read what's available from the port, and parse any complete lines into frames.
+    fn poll(&mut self) -> io::Result<()> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let n = Read::read(&mut self.port, &mut chunk)?;
+
+        for byte in &chunk[..n] {
+            match *byte {
+                CR => {
+                    // An empty line is the adapter acknowledging a command. Anything else that
+                    // doesn't parse (eg `z` transmit acks, or version responses) is ignored.
+                    if let Ok(frame) = CanFrame::decode(&self.line) {
+                        self.log(Direction::Rx, &frame);
+                        self.frames.push_back(frame);
+                    }
+                    self.line.clear();
+                }
+                BELL => {
+                    self.nack_count += 1;
+                    self.line.clear();
+                }
+                b => {
+                    // Guards against line noise without a terminator.
+                    if self.line.len() >= MAX_LINE_LEN {
+                        self.line.clear();
+                    }
+                    self.line.push(b);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Transport for SlcanTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.rx.is_empty() {
+            self.poll()?;
+
+            while let Some(frame) = self.frames.pop_front() {
+                if frame.rtr || self.rx_id.is_some_and(|id| id != frame.id) {
+                    continue;
+                }
+                self.rx.extend(frame.data());
+            }
+        }
+
+        if self.rx.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "No CAN data received",
+            ));
+        }
+
+        let n = buf.len().min(self.rx.len());
+        for (dest, byte) in buf.iter_mut().zip(self.rx.drain(..n)) {
+            *dest = byte;
+        }
+
+        Ok(n)
+    }
+
+    /// Segment the buffer into as many CAN frames as required.
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        for chunk in buf.chunks(CAN_DATA_LEN) {
+            let frame = CanFrame::new(self.tx_id, self.extended, chunk);
+            self.send_frame(&frame)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(&mut self.port)
+    }
+
+    fn describe(&self) -> String {
+        match self.port.name() {
+            Some(name) => format!("SLCAN: {name}"),
+            None => "SLCAN".to_owned(),
+        }
+    }
+
+    fn set_pcap(&mut self, pcap: Option<SharedPcapng>) {
+        self.pcap = pcap;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(frame: CanFrame, encoded: &[u8]) {
+        assert_eq!(frame.encode(), encoded);
+        assert_eq!(
+            CanFrame::decode(&encoded[..encoded.len() - 1]).unwrap(),
+            frame
+        );
+    }
+
+    #[test]
+    fn standard() {
+        round_trip(CanFrame::new(0x123, false, &[0xde, 0xad]), b"t1232DEAD\r");
+
+        let mut frame = CanFrame::new(0x7ff, false, &[]);
+        frame.timestamp = Some(0xea5f);
+        round_trip(frame, b"t7FF0EA5F\r");
+    }
+
+    #[test]
+    fn extended() {
+        round_trip(
+            CanFrame::new(0x1abc_def0, true, &[1, 2, 3, 4, 5, 6, 7, 8]),
+            b"T1ABCDEF080102030405060708\r",
+        );
+
+        let mut frame = CanFrame::new(0x100, true, &[0xff]);
+        frame.timestamp = Some(0x0001);
+        round_trip(frame, b"T000001001FF0001\r");
+    }
+
+    #[test]
+    fn remote() {
+        let mut frame = CanFrame {
+            id: 0x0a0,
+            rtr: true,
+            len: 4,
+            ..Default::default()
+        };
+        round_trip(frame, b"r0A04\r");
+
+        frame.timestamp = Some(0x1234);
+        round_trip(frame, b"r0A041234\r");
+
+        frame.extended = true;
+        frame.timestamp = None;
+        round_trip(frame, b"R000000A04\r");
+    }
+
+    #[test]
+    fn lowercase_hex() {
+        let frame = CanFrame::decode(b"t1ab1ff").unwrap();
+        assert_eq!(frame.id, 0x1ab);
+        assert_eq!(frame.data(), [0xff]);
+    }
+
+    #[test]
+    fn bad_length() {
+        // Short ID
+        assert_eq!(CanFrame::decode(b"t12"), Err(SlcanError::BadLength));
+        // Missing data
+        assert_eq!(CanFrame::decode(b"t1232DE"), Err(SlcanError::BadLength));
+        // More than 8 bytes
+        assert_eq!(CanFrame::decode(b"t1239"), Err(SlcanError::BadLength));
+        // Neither data, nor a timestamp
+        assert_eq!(CanFrame::decode(b"t1231FF00"), Err(SlcanError::BadLength));
+    }
+
+    #[test]
+    fn bad_hex() {
+        assert_eq!(CanFrame::decode(b"t12G0"), Err(SlcanError::BadHex));
+        assert_eq!(CanFrame::decode(b"t1231ZZ"), Err(SlcanError::BadHex));
+        assert_eq!(CanFrame::decode(b"t1230XXXX"), Err(SlcanError::BadHex));
+    }
+
+    #[test]
+    fn not_a_frame() {
+        assert_eq!(CanFrame::decode(b""), Err(SlcanError::Empty));
+        assert_eq!(
+            CanFrame::decode(b"z"),
+            Err(SlcanError::UnknownCommand(b'z'))
+        );
+    }
+}
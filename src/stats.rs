@@ -0,0 +1,99 @@
+//! Per-connection counters, for telling a flaky cable (CRC errors, resyncs, reconnects) from a
+//! firmware bug (timeouts on an otherwise clean link). `StateCommon::stats` is updated as data
+//! flows; display it with `ui::link_stats`.
+
+use std::time::{Duration, Instant};
+
+use crate::DecodeStats;
+
+/// Upper bounds of the latency histogram's buckets, in milliseconds. There's an extra bucket
+/// for anything slower.
+pub const LATENCY_BUCKETS_MS: [u64; 10] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000];
+
+/// Throughput is averaged over this period.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Round-trip times for `StateCommon::request`.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct LatencyHistogram {
+    /// Counts per bucket; see `LATENCY_BUCKETS_MS`.
+    pub counts: [u64; LATENCY_BUCKETS_MS.len() + 1],
+    pub samples: u64,
+    pub min: Option<Duration>,
+    pub max: Option<Duration>,
+    total: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let ms = latency.as_secs_f32() * 1_000.;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&bound| ms <= bound as f32)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+
+        self.counts[bucket] += 1;
+        self.samples += 1;
+        self.total += latency;
+        self.min = Some(self.min.map_or(latency, |min| min.min(latency)));
+        self.max = Some(self.max.map_or(latency, |max| max.max(latency)));
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.samples == 0 {
+            return None;
+        }
+        Some(self.total / self.samples as u32)
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct LinkStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub frames_sent: u64,
+    /// Valid frames.
+    pub frames_received: u64,
+    /// CRC failures, unknown message types, truncated frames, and bytes skipped to resync.
+    pub decode: DecodeStats,
+    /// Request attempts that got no reply in time, including those later retried.
+    pub timeouts: u64,
+    pub reconnects: u64,
+    pub latency: LatencyHistogram,
+    /// Bytes per second, over the last second.
+    pub tx_rate: f32,
+    pub rx_rate: f32,
+    window_start: Option<Instant>,
+    window_sent: u64,
+    window_received: u64,
+}
+
+impl LinkStats {
+    pub(crate) fn record_sent(&mut self, bytes: usize, now: Instant) {
+        self.bytes_sent += bytes as u64;
+        self.frames_sent += 1;
+        self.window_sent += bytes as u64;
+        self.update_rates(now);
+    }
+
+    pub(crate) fn record_received(&mut self, bytes: usize, now: Instant) {
+        self.bytes_received += bytes as u64;
+        self.window_received += bytes as u64;
+        self.update_rates(now);
+    }
+
+    /// Recalculate throughput, once per window. Also called from `tick`, so rates fall to 0
+    /// when the link goes quiet.
+    pub(crate) fn update_rates(&mut self, now: Instant) {
+        let start = *self.window_start.get_or_insert(now);
+        let elapsed = now.saturating_duration_since(start);
+
+        if elapsed >= THROUGHPUT_WINDOW {
+            self.tx_rate = self.window_sent as f32 / elapsed.as_secs_f32();
+            self.rx_rate = self.window_received as f32 / elapsed.as_secs_f32();
+            self.window_sent = 0;
+            self.window_received = 0;
+            self.window_start = Some(now);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Abstracts over the link to a device, so the rest of the crate (and apps) can work against
+//! USB-CDC serial, SLCAN adapters, or an in-memory loopback for testing without hardware.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+use serialport::SerialPort;
+
+use crate::pcapng::SharedPcapng;
+
+/// How long a loopback read waits for data before returning a timeout; matches the serial
+/// port's behavior.
+const LOOPBACK_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// A byte-oriented link to a device. Reads behave like a serial port with a short timeout:
+/// They return `io::ErrorKind::TimedOut` if no data arrives.
+pub trait Transport: Send {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Write the whole buffer.
+    fn write(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// A short human-readable description, eg for display in the GUI.
+    fn describe(&self) -> String;
+
+    /// Log link-level packets (eg CAN frames) to a pcapng file. Transports without a link layer
+    /// below our framing ignore this.
+    fn set_pcap(&mut self, _pcap: Option<SharedPcapng>) {}
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        (**self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Transport::flush(&mut **self)
+    }
+
+    fn describe(&self) -> String {
+        (**self).describe()
+    }
+
+    fn set_pcap(&mut self, pcap: Option<SharedPcapng>) {
+        (**self).set_pcap(pcap)
+    }
+}
+
+/// USB-CDC serial; this is what `Port` is.
+impl Transport for dyn SerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(self, buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+
+    fn describe(&self) -> String {
+        match self.name() {
+            Some(name) => format!("USB serial: {name}"),
+            None => "USB serial".to_owned(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pipe {
+    buf: Mutex<VecDeque<u8>>,
+    ready: Condvar,
+}
+
+/// One end of an in-memory link. Bytes written to one end are read from the other.
+/// Create with `Loopback::pair`.
+pub struct Loopback {
+    rx: Arc<Pipe>,
+    tx: Arc<Pipe>,
+    name: String,
+}
+
+impl Loopback {
+    /// Create two connected ends; eg one for the app, and one for a simulated device.
+    pub fn pair() -> (Self, Self) {
+        let a = Arc::new(Pipe::default());
+        let b = Arc::new(Pipe::default());
+
+        (
+            Self {
+                rx: a.clone(),
+                tx: b.clone(),
+                name: "Loopback A".to_owned(),
+            },
+            Self {
+                rx: b,
+                tx: a,
+                name: "Loopback B".to_owned(),
+            },
+        )
+    }
+}
+
+impl Transport for Loopback {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut rx = self.rx.buf.lock().unwrap();
+
+        if rx.is_empty() {
+            rx = self.rx.ready.wait_timeout(rx, LOOPBACK_TIMEOUT).unwrap().0;
+        }
+
+        if rx.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Loopback read timed out",
+            ));
+        }
+
+        let n = buf.len().min(rx.len());
+        for (dest, byte) in buf.iter_mut().zip(rx.drain(..n)) {
+            *dest = byte;
+        }
+
+        Ok(n)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.tx.buf.lock().unwrap().extend(buf);
+        self.tx.ready.notify_all();
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        self.name.clone()
+    }
+}
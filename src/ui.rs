@@ -0,0 +1,466 @@
+//! Reusable egui widgets.
+
+use std::{
+    fmt::{Debug, Write as _},
+    time::{Duration, Instant},
+};
+
+use anyleaf_usb::{DEVICE_CODE_PC, PAYLOAD_START_I};
+use eframe::egui::{self, Color32, RichText};
+
+use crate::{
+    discover, stats::LATENCY_BUCKETS_MS, Error, Frame, LinkStats, PortCandidate, StateCommon,
+};
+
+/// A "choose device" row: a dropdown of discovered ports, a refresh button, connect and
+/// disconnect, and the connection status. Keep one of these in app state, and call `ui` each
+/// frame.
+#[derive(Default)]
+pub struct PortPicker {
+    ports: Vec<PortCandidate>,
+    /// The chosen port name. `None` searches by serial number. This may not be in `ports`; eg a
+    /// `VirtualDevice` path, or a device that's been unplugged.
+    selected: Option<String>,
+    /// Set from a failed refresh or connect; cleared on the next attempt.
+    error: Option<String>,
+    refreshed: bool,
+}
+
+impl PortPicker {
+    /// List ports again; eg after plugging in a device.
+    pub fn refresh(&mut self) {
+        self.refreshed = true;
+        self.error = None;
+
+        match discover() {
+            Ok(ports) => self.ports = ports,
+            Err(e) => {
+                self.ports.clear();
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Connect using the chosen port, or by searching if none is chosen.
+    fn connect(&mut self, state: &mut StateCommon) {
+        self.error = None;
+        state.port_override = self.selected.clone();
+
+        // Close the current port first; the new choice may find the same device.
+        state.disconnect();
+        if let Err(e) = state.connect() {
+            self.error = Some(e.to_string());
+        }
+    }
+
+    pub fn ports(&self) -> &[PortCandidate] {
+        &self.ports
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui, state: &mut StateCommon) {
+        // Populate the list on first display.
+        if !self.refreshed {
+            self.selected = state.port_override.clone();
+            self.refresh();
+        }
+
+        ui.horizontal(|ui| {
+            let previous = self.selected.clone();
+            // Keep showing a chosen port that isn't listed, instead of dropping it.
+            let unlisted = self
+                .selected
+                .clone()
+                .filter(|selected| !self.ports.iter().any(|p| &p.port_name == selected));
+
+            let selected_text = match &self.selected {
+                Some(port_name) => port_name.clone(),
+                None => "Automatic".to_owned(),
+            };
+
+            egui::ComboBox::from_id_salt("port_picker")
+                .selected_text(selected_text)
+                .width(240.)
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.selected, None, "Automatic");
+
+                    if let Some(port_name) = unlisted {
+                        let label = format!("{port_name} (not listed)");
+                        ui.selectable_value(&mut self.selected, Some(port_name), label);
+                    }
+
+                    for port in &self.ports {
+                        ui.selectable_value(
+                            &mut self.selected,
+                            Some(port.port_name.clone()),
+                            port_label(port),
+                        );
+                    }
+                });
+
+            // Switch to a newly chosen port right away, unless we've been disconnected.
+            if self.selected != previous && !state.is_disconnected() {
+                self.connect(state);
+            }
+
+            if ui.button("Refresh").clicked() {
+                self.refresh();
+            }
+
+            // Show "Disconnect" while we're connected or trying to connect.
+            if state.is_disconnected() {
+                if ui.button("Connect").clicked() {
+                    self.connect(state);
+                }
+            } else if ui.button("Disconnect").clicked() {
+                state.disconnect();
+            }
+
+            ui.label(
+                RichText::new(state.connection_status.as_str())
+                    .color(state.connection_status.as_color()),
+            );
+        });
+
+        if let Some(error) = &self.error {
+            ui.label(RichText::new(error).color(Color32::LIGHT_RED));
+        }
+    }
+}
+
+/// eg `/dev/ttyACM0: Corvus (USB, 0483:5740)`.
+fn port_label(port: &PortCandidate) -> String {
+    let mut details = vec![port.connection_type.as_str().to_owned()];
+    if let (Some(vid), Some(pid)) = (port.vid, port.pid) {
+        details.push(format!("{vid:04x}:{pid:04x}"));
+    }
+
+    match &port.product {
+        Some(product) => format!("{}: {product} ({})", port.port_name, details.join(", ")),
+        None => format!("{} ({})", port.port_name, details.join(", ")),
+    }
+}
+
+/// Show link counters, throughput, and request latency; eg in a diagnostics panel.
+pub fn link_stats(ui: &mut egui::Ui, stats: &LinkStats) {
+    egui::Grid::new("link_stats").striped(true).show(ui, |ui| {
+        let mut row = |label: &str, value: String| {
+            ui.label(label);
+            ui.label(value);
+            ui.end_row();
+        };
+
+        row(
+            "Sent",
+            format!("{} frames, {} bytes", stats.frames_sent, stats.bytes_sent),
+        );
+        row(
+            "Received",
+            format!(
+                "{} frames, {} bytes",
+                stats.frames_received, stats.bytes_received
+            ),
+        );
+        row(
+            "Throughput",
+            format!("TX {:.0} B/s, RX {:.0} B/s", stats.tx_rate, stats.rx_rate),
+        );
+        row("CRC errors", stats.decode.bad_crc.to_string());
+        row("Unknown types", stats.decode.unknown_type.to_string());
+        row("Truncated", stats.decode.truncated.to_string());
+        row("Resync bytes", stats.decode.discarded_bytes.to_string());
+        row("Timeouts", stats.timeouts.to_string());
+        row("Reconnects", stats.reconnects.to_string());
+
+        let latency = &stats.latency;
+        let fmt_ms = |d: Option<Duration>| match d {
+            Some(d) => format!("{:.1}", d.as_secs_f32() * 1_000.),
+            None => "-".to_owned(),
+        };
+        row(
+            "Latency (ms)",
+            format!(
+                "min {}, mean {}, max {}",
+                fmt_ms(latency.min),
+                fmt_ms(latency.mean()),
+                fmt_ms(latency.max)
+            ),
+        );
+    });
+
+    if stats.latency.samples == 0 {
+        return;
+    }
+
+    ui.label("Latency histogram");
+    let most = *stats.latency.counts.iter().max().unwrap_or(&1);
+
+    egui::Grid::new("link_stats_latency").show(ui, |ui| {
+        for (i, &count) in stats.latency.counts.iter().enumerate() {
+            let label = match LATENCY_BUCKETS_MS.get(i) {
+                Some(bound) => format!("≤ {bound} ms"),
+                None => format!("> {} ms", LATENCY_BUCKETS_MS[LATENCY_BUCKETS_MS.len() - 1]),
+            };
+
+            ui.label(label);
+            ui.add(
+                egui::ProgressBar::new(count as f32 / most.max(1) as f32)
+                    .desired_width(160.)
+                    .text(count.to_string()),
+            );
+            ui.end_row();
+        }
+    });
+}
+
+/// A window showing the connection, link statistics, and recent frames in hex. `T` is used to
+/// name message types. Frames are only shown if `StateCommon::frame_log` is set. Pass `open` to
+/// give the window a close button.
+pub fn diagnostics_window<T: TryFrom<u8> + Debug>(
+    ctx: &egui::Context,
+    state: &StateCommon,
+    open: &mut bool,
+) {
+    egui::Window::new("Link diagnostics")
+        .open(open)
+        .default_width(720.)
+        .show(ctx, |ui| {
+            egui::Grid::new("diagnostics_connection").show(ui, |ui| {
+                let port = match (&state.interface.port_name, &state.interface.transport) {
+                    (Some(name), _) => name.clone(),
+                    (None, Some(transport)) => transport.describe(),
+                    (None, None) => "None".to_owned(),
+                };
+
+                ui.label("Port");
+                ui.label(port);
+                ui.end_row();
+
+                ui.label("Type");
+                ui.label(state.interface.connection_type.as_str());
+                ui.end_row();
+
+                ui.label("Status");
+                ui.label(
+                    RichText::new(state.connection_status.as_str())
+                        .color(state.connection_status.as_color()),
+                );
+                ui.end_row();
+
+                ui.label("Last response");
+                ui.label(format!(
+                    "{:.1} s ago",
+                    state.last_response.elapsed().as_secs_f32()
+                ));
+                ui.end_row();
+            });
+
+            egui::CollapsingHeader::new("Statistics")
+                .default_open(true)
+                .show(ui, |ui| link_stats(ui, &state.stats));
+
+            let Some(log) = &state.frame_log else {
+                ui.label("Set `StateCommon::frame_log` to show recent frames.");
+                return;
+            };
+
+            let now = Instant::now();
+
+            ui.columns(2, |columns| {
+                for (ui, (heading, frames)) in columns
+                    .iter_mut()
+                    .zip([("Sent", &log.sent), ("Received", &log.received)])
+                {
+                    ui.heading(heading);
+
+                    egui::ScrollArea::vertical()
+                        .id_salt(heading)
+                        .max_height(300.)
+                        .stick_to_bottom(true)
+                        .show(ui, |ui| {
+                            for frame in frames {
+                                let msg_type = match frame.msg_type() {
+                                    Some(val) => match T::try_from(val) {
+                                        Ok(msg_type) => format!("{msg_type:?}"),
+                                        Err(_) => format!("Unknown ({val})"),
+                                    },
+                                    None => "-".to_owned(),
+                                };
+
+                                ui.monospace(format!(
+                                    "-{:>7.3} s  {msg_type}\n{}",
+                                    now.saturating_duration_since(frame.time).as_secs_f32(),
+                                    hex(&frame.bytes)
+                                ));
+                            }
+                        });
+                }
+            });
+        });
+}
+
+/// Format bytes as space-separated hex; eg `ab 01 ff`.
+pub fn hex(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity(bytes.len() * 3);
+    for (i, byte) in bytes.iter().enumerate() {
+        if i > 0 {
+            result.push(' ');
+        }
+        write!(result, "{byte:02x}").unwrap();
+    }
+    result
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum TerminalInput {
+    /// eg `ab 01 ff`, or `ab01ff`.
+    #[default]
+    Hex,
+    Ascii,
+}
+
+/// A raw terminal, for poking at firmware before the app supports a message type. Type bytes in
+/// hex or ASCII, and send them as-is, or as a frame's payload. Sent bytes, and received bytes as
+/// read from the port, are shown with timestamps. This uses `StateCommon::frame_log`, creating it
+/// if needed. Received data shows as the app calls `receive`.
+#[derive(Default)]
+pub struct Terminal {
+    pub input: String,
+    pub input_mode: TerminalInput,
+    /// If set, the input is the payload of a frame with this message type. Otherwise, it's sent
+    /// as-is.
+    pub frame_msg_type: Option<u8>,
+    error: Option<String>,
+}
+
+impl Terminal {
+    pub fn ui(&mut self, ui: &mut egui::Ui, state: &mut StateCommon) {
+        let log = state.frame_log.get_or_insert_with(Default::default);
+
+        // Sent and received, interleaved by time.
+        let mut lines: Vec<_> = log
+            .sent
+            .iter()
+            .map(|f| (f.time, "TX", f))
+            .chain(log.raw_received.iter().map(|f| (f.time, "RX", f)))
+            .collect();
+        lines.sort_by_key(|(time, _, _)| *time);
+
+        let now = Instant::now();
+
+        egui::ScrollArea::vertical()
+            .id_salt("terminal")
+            .max_height(300.)
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                for (time, direction, frame) in lines {
+                    ui.monospace(format!(
+                        "-{:>7.3} s {direction}  {}  |{}|",
+                        now.saturating_duration_since(time).as_secs_f32(),
+                        hex(&frame.bytes),
+                        ascii(&frame.bytes)
+                    ));
+                }
+            });
+
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut self.input_mode, TerminalInput::Hex, "Hex");
+            ui.selectable_value(&mut self.input_mode, TerminalInput::Ascii, "ASCII");
+
+            let mut framed = self.frame_msg_type.is_some();
+            ui.checkbox(&mut framed, "Framed, type:");
+
+            let mut msg_type = self.frame_msg_type.unwrap_or_default();
+            ui.add_enabled(framed, egui::DragValue::new(&mut msg_type));
+            self.frame_msg_type = framed.then_some(msg_type);
+
+            if ui.button("Clear").clicked() {
+                log.clear();
+            }
+        });
+
+        let mut send = false;
+        ui.horizontal(|ui| {
+            let response = ui.add(
+                egui::TextEdit::singleline(&mut self.input)
+                    .font(egui::TextStyle::Monospace)
+                    .desired_width(400.),
+            );
+            send = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            send |= ui.button("Send").clicked();
+        });
+
+        if send {
+            self.error = self.send(state).err();
+        }
+
+        if let Some(error) = &self.error {
+            ui.label(RichText::new(error).color(Color32::LIGHT_RED));
+        }
+    }
+
+    fn send(&mut self, state: &mut StateCommon) -> Result<(), String> {
+        let bytes = match self.input_mode {
+            TerminalInput::Hex => parse_hex(&self.input)?,
+            TerminalInput::Ascii => self.input.as_bytes().to_vec(),
+        };
+
+        let bytes = match self.frame_msg_type {
+            Some(msg_type) => {
+                let max = u8::MAX as usize - PAYLOAD_START_I;
+                if bytes.len() > max {
+                    return Err(Error::PayloadTooLarge {
+                        size: bytes.len(),
+                        max,
+                    }
+                    .to_string());
+                }
+
+                Frame {
+                    device_code: DEVICE_CODE_PC,
+                    msg_type,
+                    payload: bytes,
+                }
+                .to_bytes()
+            }
+            None => bytes,
+        };
+
+        state.send_raw(&bytes).map_err(|e| e.to_string())
+    }
+}
+
+/// Parse space-separated or contiguous hex, with optional `0x` prefixes.
+fn parse_hex(input: &str) -> Result<Vec<u8>, String> {
+    let digits: String = input
+        .split_whitespace()
+        .map(|word| word.trim_start_matches("0x").trim_start_matches("0X"))
+        .collect();
+
+    if !digits.len().is_multiple_of(2) {
+        return Err("Hex input needs an even number of digits".to_owned());
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            digits
+                .get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("Invalid hex: {input}"))
+        })
+        .collect()
+}
+
+/// Printable ASCII, with other bytes shown as `.`.
+fn ascii(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
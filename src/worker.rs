@@ -0,0 +1,195 @@
+//! An optional background thread that owns the port, so serial I/O and port enumeration don't
+//! stall the GUI. `StateCommon` talks to it over channels once started with
+//! `StateCommon::start_worker`.
+
+use std::{
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyleaf_usb::MessageType;
+use eframe::egui;
+
+use crate::{ConnectionStatus, ConnectionType, Error, Frame, HotplugEvent, LinkStats, StateCommon};
+
+/// How long the worker sleeps between connection attempts while there's no open port. While
+/// connected, the port's read timeout paces the loop instead.
+const IDLE_SLEEP_MS: u64 = 10;
+
+pub enum WorkerCmd {
+    /// Write these bytes to the port; they're already framed.
+    Send(Vec<u8>),
+    /// Connect now, using this port override; see `StateCommon::connect`.
+    Connect(Option<String>),
+    /// Close the port, and stop reconnecting until `Connect`.
+    Disconnect,
+    Shutdown,
+}
+
+pub enum WorkerEvent {
+    Frame(Frame<u8>),
+    /// Bytes as read from the port, including any that aren't part of a valid frame; see
+    /// `FrameLog::raw_received`.
+    Raw(Vec<u8>),
+    Status(ConnectionStatus),
+    /// Sent when the worker opens a port, or closes it; `port_name` is `None` once closed, or
+    /// for an attached transport.
+    Port {
+        port_name: Option<String>,
+        connection_type: ConnectionType,
+    },
+    Hotplug(HotplugEvent),
+    /// Sent when the worker's counts change.
+    Stats(LinkStats),
+    Error(Error),
+}
+
+/// A handle to the I/O thread. Dropping it stops the thread.
+pub struct Worker {
+    cmd_tx: Sender<WorkerCmd>,
+    pub event_rx: Receiver<WorkerEvent>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    /// Start the thread. It handles the connection using `state`, and decodes frames using
+    /// message type `T`. If `ctx` is passed, the GUI is woken when something arrives.
+    pub fn spawn<T: MessageType + TryFrom<u8> + 'static>(
+        state: StateCommon,
+        ctx: Option<egui::Context>,
+    ) -> Self {
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        let (event_tx, event_rx) = mpsc::channel();
+
+        let handle = thread::spawn(move || run::<T>(state, cmd_rx, event_tx, ctx));
+
+        Self {
+            cmd_tx,
+            event_rx,
+            handle: Some(handle),
+        }
+    }
+
+    /// Queue framed bytes to be written.
+    pub fn send(&self, bytes: Vec<u8>) -> Result<(), Error> {
+        self.cmd(WorkerCmd::Send(bytes))
+    }
+
+    /// Connect, opening `port_override` if set, or searching by serial number otherwise.
+    pub fn connect(&self, port_override: Option<String>) -> Result<(), Error> {
+        self.cmd(WorkerCmd::Connect(port_override))
+    }
+
+    pub fn disconnect(&self) -> Result<(), Error> {
+        self.cmd(WorkerCmd::Disconnect)
+    }
+
+    fn cmd(&self, cmd: WorkerCmd) -> Result<(), Error> {
+        self.cmd_tx
+            .send(cmd)
+            .map_err(|_| Error::Io(std::io::Error::other("The I/O worker has stopped")))
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.cmd_tx.send(WorkerCmd::Shutdown).ok();
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+/// The worker thread's loop. It uses its own `StateCommon` for connection handling.
+fn run<T: MessageType + TryFrom<u8>>(
+    mut state: StateCommon,
+    cmd_rx: Receiver<WorkerCmd>,
+    event_tx: Sender<WorkerEvent>,
+    ctx: Option<egui::Context>,
+) {
+    let notify = |event: WorkerEvent| {
+        // If the handle's gone, we're about to receive `Shutdown`, or see the channel close.
+        event_tx.send(event).ok();
+        if let Some(ctx) = &ctx {
+            ctx.request_repaint();
+        }
+    };
+
+    let mut status = state.connection_status.clone();
+    let mut port_name = state.interface.port_name.clone();
+    let mut stats = state.stats.clone();
+
+    loop {
+        loop {
+            match cmd_rx.try_recv() {
+                Ok(WorkerCmd::Send(bytes)) => {
+                    // This updates `last_query`, and flags I/O errors for reconnection.
+                    if let Err(e) = state.write_frame(&bytes) {
+                        notify(WorkerEvent::Error(e));
+                    }
+                }
+                Ok(WorkerCmd::Connect(port_override)) => {
+                    state.port_override = port_override;
+                    if let Err(e) = state.connect() {
+                        notify(WorkerEvent::Error(e));
+                    }
+                }
+                Ok(WorkerCmd::Disconnect) => state.disconnect(),
+                Ok(WorkerCmd::Shutdown) | Err(TryRecvError::Disconnected) => return,
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        state.tick(Instant::now());
+
+        for event in state.take_hotplug_events() {
+            notify(WorkerEvent::Hotplug(event));
+        }
+
+        if state.connection_status.is_open() {
+            loop {
+                let received = state.receive::<T>();
+
+                if let Some(log) = &mut state.frame_log {
+                    for raw in log.raw_received.drain(..) {
+                        notify(WorkerEvent::Raw(raw.bytes));
+                    }
+                }
+
+                match received {
+                    Ok(Some(frame)) => notify(WorkerEvent::Frame(frame.into_raw())),
+                    Ok(None) | Err(Error::Timeout) => break,
+                    // Bad frames don't stop us reading the ones after them.
+                    Err(e @ (Error::Crc(_) | Error::ProtocolMismatch(_))) => {
+                        notify(WorkerEvent::Error(e))
+                    }
+                    Err(e) => {
+                        notify(WorkerEvent::Error(e));
+                        break;
+                    }
+                }
+            }
+        } else {
+            thread::sleep(Duration::from_millis(IDLE_SLEEP_MS));
+        }
+
+        if state.connection_status != status {
+            status = state.connection_status.clone();
+            notify(WorkerEvent::Status(status.clone()));
+        }
+
+        if state.interface.port_name != port_name {
+            port_name = state.interface.port_name.clone();
+            notify(WorkerEvent::Port {
+                port_name: port_name.clone(),
+                connection_type: state.interface.connection_type,
+            });
+        }
+
+        if state.stats != stats {
+            stats = state.stats.clone();
+            notify(WorkerEvent::Stats(stats.clone()));
+        }
+    }
+}